@@ -1,4 +1,13 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
 use bevy::prelude::*;
+use bevy::render::render_resource::TextureFormat;
+use bevy::utils::Instant;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 const SIM_HEIGHT: f32 = 1.0;
 const OBSTACLE_ZERO: Vec2 = Vec2::ZERO;
@@ -7,15 +16,248 @@ const DENSITY: f32 = 1000.0;
 const GRAVITY: f32 = -9.81;
 const NUMBER_ITERATIONS: usize = 40;
 const OVERRELAXATION: f32 = 1.9;
+// SOR diverges at 2 and beyond
+pub const MAX_OVERRELAXATION: f32 = 1.99;
 const TIMESTEP: f32 = 1.0 / 60.0;
-const VELOCITY_IN: f32 = 2.0;
+pub const VELOCITY_IN: f32 = 2.0;
+const LINE_SOURCE_SPACING: usize = 8;
+const RING_SOURCE_OFFSET: f32 = 0.2;
+const RING_SOURCE_RADIUS: f32 = 0.05;
+const REFINEMENT_FACTOR: usize = 2;
+const REFINEMENT_SIZE: usize = 30;
+const HISTOGRAM_BINS: usize = 20;
+const MAX_SMOKE: f32 = 1.0;
+const ARROW_SCALE: f32 = 0.01;
+// Plume source at the bottom of the tank: half width, and rise speed at full rate
+const TANK_SOURCE_HALF_WIDTH: f32 = 0.05;
+const TANK_SOURCE_SPEED: f32 = 0.5;
+// Limits beyond which a scene is considered to be diverging
+const STABLE_MAX_VELOCITY: f32 = 100.0;
+// Semi-Lagrangian advection tolerates Courant numbers above 1, only runaway values are flagged
+const STABLE_MAX_COURANT: f32 = 10.0;
+// Color of cells whose Courant number exceeds 1
+const COURANT_WARNING: [f32; 3] = [255.0, 0.0, 255.0];
+// Wake probe distance downstream of the obstacle center, in obstacle radii
+const WAKE_PROBE_OFFSET: f32 = 4.0;
+const WAKE_HISTORY_LENGTH: usize = 600;
+// Peak tangential speed of the shedding kick, relative to the inflow speed
+const SHEDDING_KICK: f32 = 0.5;
+// Largest obstacle radius, as a fraction of the domain height
+const MAX_OBSTACLE_RADIUS: f32 = 0.4;
+// Frames kept for stepping backward by scenes that opt in to recording history
+pub const HISTORY_DEPTH: usize = 120;
+const LIC_KERNEL_LENGTH: usize = 20;
+// Default cap on the drag velocity of the obstacle in m/s, well above normal dragging
+const MAX_OBSTACLE_SPEED: f32 = 10.0;
+// How long a clamped drag keeps the speed warning up
+const OBSTACLE_SPEED_WARNING: Duration = Duration::from_secs(1);
+// Sweeps of the implicit viscosity solve
+const VISCOSITY_ITERATIONS: usize = 20;
+// Most substeps a single advance call takes
+const MAX_SUBSTEPS: usize = 8;
+// Rate in 1/s at which velocity right next to a wall relaxes in the clamp border
+const BORDER_RELAXATION_RATE: f32 = 5.0;
+const STREAM_FUNCTION_ITERATIONS: usize = 100;
+const OBSTACLE_SUBSAMPLES: usize = 4;
+// Contour lines drawn across the range of the stream function
+const STREAM_CONTOURS: f32 = 24.0;
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum SceneType {
     WindTunnel,
     HiresTunnel,
     Tank,
     Paint,
+    BackwardStep,
+    Coanda,
+}
+
+impl SceneType {
+    pub fn all() -> &'static [SceneType] {
+        &[
+            SceneType::WindTunnel,
+            SceneType::HiresTunnel,
+            SceneType::Tank,
+            SceneType::Paint,
+            SceneType::BackwardStep,
+            SceneType::Coanda,
+        ]
+    }
+
+    // Human readable name, Debug keeps the variant name
+    pub fn label(self) -> &'static str {
+        match self {
+            SceneType::WindTunnel => "Wind Tunnel",
+            SceneType::HiresTunnel => "Hires Tunnel",
+            SceneType::Tank => "Tank",
+            SceneType::Paint => "Paint",
+            SceneType::BackwardStep => "Backward Step",
+            SceneType::Coanda => "Coanda Jet",
+        }
+    }
+}
+
+impl std::fmt::Display for SceneType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RenderFormat {
+    // Colored visualization
+    Rgba,
+    // Single channel data textures holding one normalized scalar
+    Pressure,
+    Smoke,
+}
+
+impl RenderFormat {
+    pub fn channels(&self) -> usize {
+        match self {
+            RenderFormat::Rgba => 4,
+            RenderFormat::Pressure | RenderFormat::Smoke => 1,
+        }
+    }
+
+    pub fn texture_format(&self) -> TextureFormat {
+        match self {
+            RenderFormat::Rgba => TextureFormat::Rgba8Unorm,
+            RenderFormat::Pressure | RenderFormat::Smoke => TextureFormat::R8Unorm,
+        }
+    }
+}
+
+// Color ramp the pressure and the smoke gradient are drawn with
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ColorMap {
+    // Blue, cyan, green, yellow, red
+    #[default]
+    SciColor,
+    Grayscale,
+    // Perceptually uniform, dark purple to yellow
+    Viridis,
+    // Black through red and yellow to white
+    BlackBody,
+}
+
+impl ColorMap {
+    pub const ALL: [ColorMap; 4] = [ColorMap::SciColor, ColorMap::Grayscale, ColorMap::Viridis, ColorMap::BlackBody];
+
+    const VIRIDIS: [[f32; 3]; 5] = [
+        [68.0, 1.0, 84.0],
+        [59.0, 82.0, 139.0],
+        [33.0, 145.0, 140.0],
+        [94.0, 201.0, 98.0],
+        [253.0, 231.0, 37.0],
+    ];
+
+    const BLACK_BODY: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [160.0, 0.0, 0.0],
+        [255.0, 120.0, 0.0],
+        [255.0, 230.0, 60.0],
+        [255.0, 255.0, 255.0],
+    ];
+
+    // Color for t in [0, 1] with channels in [0, 255], t outside is clamped
+    pub fn map(&self, t: f32) -> [f32; 3] {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            ColorMap::SciColor => get_sci_color(t, 0.0, 1.0),
+            ColorMap::Grayscale => [255.0 * t; 3],
+            ColorMap::Viridis => ramp(&Self::VIRIDIS, t),
+            ColorMap::BlackBody => ramp(&Self::BLACK_BODY, t),
+        }
+    }
+
+    // Color for value in [min, max], the middle of the map when the range is empty
+    pub fn map_range(&self, value: f32, min: f32, max: f32) -> [f32; 3] {
+        let d = max - min;
+        self.map(if d == 0.0 { 0.5 } else { (value - min) / d })
+    }
+}
+
+// Linear interpolation between evenly spaced color stops
+fn ramp(stops: &[[f32; 3]], t: f32) -> [f32; 3] {
+    let x = t * (stops.len() - 1) as f32;
+    let k = (x as usize).min(stops.len() - 2);
+    let f = x - k as f32;
+    let (a, b) = (stops[k], stops[k + 1]);
+    [a[0] + f * (b[0] - a[0]), a[1] + f * (b[1] - a[1]), a[2] + f * (b[2] - a[2])]
+}
+
+// Geometry of the smoke emitted at the inlet of scenes with inflow
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum InletSource {
+    // Band across the middle of the inlet
+    Band,
+    // Single source at the middle of the inlet
+    Point,
+    // Evenly spaced sources along the inlet
+    Line,
+    // Ring of sources just downstream of the inlet
+    Ring,
+}
+
+// Initial smoke layouts that make the deformation by the flow easy to follow
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SmokePattern {
+    Checkerboard,
+    // Dense on the left, fading to clear on the right
+    Gradient,
+    // Single disc in the middle of the domain
+    Blob,
+    Clear,
+}
+
+// What smoke does where it meets solid cells
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum WallSmokeMode {
+    // Clear smoke in solid cells is mixed in, so smoke fades out along walls
+    Absorb,
+    // Solid cells are left out of the smoke interpolation, so smoke piles up against walls
+    Reflect,
+}
+
+// Mirror line used to keep the velocity field symmetric
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SymmetryAxis {
+    // Mirror about the horizontal centerline, y -> height - y
+    Horizontal,
+    // Mirror about the vertical centerline, x -> width - x
+    Vertical,
+}
+
+// How body forces are integrated together with the velocity dependent porous drag and damping.
+// Without drag both are exact for constant gravity, with drag they differ in how fast the flow
+// approaches its terminal velocity g / k.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TimeIntegration {
+    // First order, drag taken implicitly (backward Euler) so it stays stable
+    Euler,
+    // Second order trapezoidal update, the velocity Verlet form for linear drag
+    Verlet,
+}
+
+impl TimeIntegration {
+    // Factor a velocity is scaled by over dt under drag coefficient k. The trapezoidal factor
+    // turns negative for k * dt > 2, it is clamped so stiff drag stops the flow instead of
+    // flipping it every step
+    fn drag_factor(&self, k: f32, dt: f32) -> f32 {
+        match self {
+            TimeIntegration::Euler => 1.0 / (1.0 + k * dt),
+            TimeIntegration::Verlet => ((1.0 - 0.5 * k * dt) / (1.0 + 0.5 * k * dt)).max(0.0),
+        }
+    }
+
+    // Factor the velocity change force * dt of a constant force is scaled by under drag k
+    fn force_gain(&self, k: f32, dt: f32) -> f32 {
+        match self {
+            TimeIntegration::Euler => 1.0 / (1.0 + k * dt),
+            TimeIntegration::Verlet => 1.0 / (1.0 + 0.5 * k * dt),
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -23,28 +265,557 @@ pub enum Field {
     U,
     V,
     S,
+    P,
+}
+
+// Fields interpolated at a point
+#[derive(Clone, Copy, Debug)]
+pub struct FluidSample {
+    pub velocity: Vec2,
+    pub pressure: f32,
+    // Amount of dye, 0 in clear fluid and 1 in full dye, the inverse of the m field
+    pub smoke: f32,
+    // Whether the point lies in a solid cell, the values are still interpolated there
+    pub solid: bool,
+}
+
+// Derived quantity read off at the points of a probe grid
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ProbeQuantity {
+    Speed,
+    Pressure,
+    // Counterclockwise rotation, negative for clockwise
+    Vorticity,
+}
+
+// Body force sampled per face as f(i, j, x, y), returning an acceleration. The x component is
+// used on u faces and the y component on v faces.
+pub type ForceField = Box<dyn FnMut(usize, usize, f32, f32) -> Vec2 + Send + Sync>;
+
+// Solver quality measurements taken right after the pressure solve
+#[derive(Default)]
+pub struct Diagnostics {
+    pub max_divergence: f32,
+    // Counts of fluid cells by divergence magnitude, bins span 0 to max_divergence
+    pub divergence_histogram: Vec<u32>,
+    // Max divergence after each solver sweep of the last step, filled when record_residuals is on
+    pub residual_history: Vec<f32>,
+    // Number of separate connected fluid regions
+    pub fluid_regions: usize,
+}
+
+impl Diagnostics {
+    // The recorded residual ended above where it started, so the sweeps made the field worse
+    pub fn residual_growing(&self) -> bool {
+        match (self.residual_history.first(), self.residual_history.last()) {
+            (Some(first), Some(last)) => last > first,
+            _ => false,
+        }
+    }
+}
+
+// |divergence| left by projecting one frame's velocities with different iteration counts
+pub struct IterationComparison {
+    pub frame_nr: usize,
+    // Iteration count and the field as returned by Fluid::divergence_field
+    pub fields: Vec<(usize, Vec<f32>)>,
+}
+
+// Wall clock time spent in each stage of the last step, filled when profiling is on
+#[derive(Default, Clone, Copy, Debug)]
+pub struct StepTimings {
+    pub integrate: Duration,
+    pub projection: Duration,
+    pub extrapolate: Duration,
+    pub advect_vel: Duration,
+    pub advect_smoke: Duration,
+    pub refinement: Duration,
+    pub draw: Duration,
+}
+
+impl StepTimings {
+    pub fn stages(&self) -> [(&'static str, Duration); 7] {
+        [
+            ("Integrate", self.integrate),
+            ("Projection", self.projection),
+            ("Extrapolate", self.extrapolate),
+            ("Advect velocity", self.advect_vel),
+            ("Advect smoke", self.advect_smoke),
+            ("Refinement", self.refinement),
+            ("Draw", self.draw),
+        ]
+    }
+}
+
+// Stores the time since the clock was last read and restarts it, does nothing without a clock
+fn lap(clock: &mut Option<Instant>, stage: &mut Duration) {
+    if let Some(start) = clock {
+        let now = Instant::now();
+        *stage = now - *start;
+        *start = now;
+    }
+}
+
+// Visualization toggles and the colormap, grouped so they can be carried across scene changes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ViewSettings {
+    pub show_streamlines: bool,
+    pub show_velocities: bool,
+    pub show_pressure: bool,
+    pub show_smoke: bool,
+    pub show_smoke_gradient: bool,
+    pub show_courant: bool,
+    pub show_mask: bool,
+    #[serde(default)]
+    pub colormap: ColorMap,
+}
+
+impl ViewSettings {
+    pub fn scene_default(scene_type: SceneType) -> Self {
+        let mut view = ViewSettings {
+            show_streamlines: false,
+            show_velocities: false,
+            show_pressure: false,
+            show_smoke: true,
+            show_smoke_gradient: false,
+            show_courant: false,
+            show_mask: false,
+            colormap: ColorMap::default(),
+        };
+
+        match scene_type {
+            SceneType::WindTunnel => {}
+            SceneType::HiresTunnel => view.show_pressure = true,
+            SceneType::Tank => view.show_pressure = true,
+            SceneType::Paint => view.show_smoke_gradient = true,
+            SceneType::BackwardStep | SceneType::Coanda => view.show_streamlines = true,
+        }
+
+        view
+    }
+}
+
+// View toggles a config file sets for one scene type, None keeps the built-in default
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct ViewOverrides {
+    pub show_streamlines: Option<bool>,
+    pub show_velocities: Option<bool>,
+    pub show_pressure: Option<bool>,
+    pub show_smoke: Option<bool>,
+    pub show_smoke_gradient: Option<bool>,
+    pub show_courant: Option<bool>,
+    pub show_mask: Option<bool>,
+    pub colormap: Option<ColorMap>,
+}
+
+// Per scene view defaults, e.g. {"scenes": {"Tank": {"show_pressure": false, "colormap": "Viridis"}}}
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ViewConfig {
+    #[serde(default)]
+    pub scenes: HashMap<SceneType, ViewOverrides>,
+}
+
+impl ViewConfig {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    // Built-in defaults with this config's overrides for the scene type applied
+    pub fn scene_default(&self, scene_type: SceneType) -> ViewSettings {
+        let mut view = ViewSettings::scene_default(scene_type);
+        let Some(overrides) = self.scenes.get(&scene_type) else {
+            return view;
+        };
+        let toggles = [
+            (&mut view.show_streamlines, overrides.show_streamlines),
+            (&mut view.show_velocities, overrides.show_velocities),
+            (&mut view.show_pressure, overrides.show_pressure),
+            (&mut view.show_smoke, overrides.show_smoke),
+            (&mut view.show_smoke_gradient, overrides.show_smoke_gradient),
+            (&mut view.show_courant, overrides.show_courant),
+            (&mut view.show_mask, overrides.show_mask),
+        ];
+        for (toggle, value) in toggles {
+            if let Some(value) = value {
+                *toggle = value;
+            }
+        }
+        if let Some(colormap) = overrides.colormap {
+            view.colormap = colormap;
+        }
+        view
+    }
+}
+
+// Obstacle as stored in a layout file, in simulation coordinates
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ObstacleConfig {
+    pub position: [f32; 2],
+    pub radius: f32,
+    #[serde(default)]
+    pub velocity: [f32; 2],
+    #[serde(default)]
+    pub rotation_rate: f32,
+}
+
+// Circular obstacle in simulation coordinates
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Obstacle {
+    pub pos: Vec2,
+    pub radius: f32,
+    pub vel: Vec2,
+}
+
+// State before a step, restored by step_back. The obstacles are kept so they don't jump away
+// from the solid cells of the recorded fluid
+struct HistoryEntry {
+    fluid: Fluid,
+    frame_nr: usize,
+    sim_time: f32,
+    obstacles: Vec<Obstacle>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ObstacleLayout {
+    obstacles: Vec<ObstacleConfig>,
+}
+
+// Everything needed to rebuild a scene: its grid, tunable parameters, obstacles, painted regions
+// and view. The flow itself and runtime state such as the frame count are left out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SceneSettings {
+    pub scene_type: SceneType,
+    // Image size the scene draws into
+    pub width: f32,
+    pub height: f32,
+    // Interior cells, without the boundary layer
+    pub cells: [usize; 2],
+    pub cell_size: f32,
+    pub scale: f32,
+    pub gravity: f32,
+    pub fixed_dt: f32,
+    pub manual_dt: Option<f32>,
+    pub num_iters: usize,
+    pub over_relaxation: f32,
+    pub local_relaxation: bool,
+    pub time_integration: TimeIntegration,
+    pub enforce_symmetry: Option<SymmetryAxis>,
+    pub obstacles: Vec<ObstacleConfig>,
+    pub obstacle_smoothing: f32,
+    pub max_obstacle_speed: f32,
+    #[serde(default)]
+    pub vorticity_strength: f32,
+    #[serde(default)]
+    pub viscosity: f32,
+    pub obstacle_feather: f32,
+    pub obstacle_subsamples: usize,
+    // Circle the fluid is restricted to as (center, radius) in simulation coordinates
+    pub circular_domain: Option<([f32; 2], f32)>,
+    pub tank_source_rate: f32,
+    pub tank_drain_speed: f32,
+    pub inflow_ramp_seconds: f32,
+    pub inflow_profile: Option<Vec<f32>>,
+    pub inlet_source: InletSource,
+    pub continuous_inlet_smoke: bool,
+    pub inlet_band_fraction: f32,
+    pub initial_smoke: f32,
+    pub wall_smoke_mode: WallSmokeMode,
+    pub max_smoke: f32,
+    pub trail_decay: f32,
+    pub clamp_border: usize,
+    #[serde(default)]
+    pub damping_x: f32,
+    #[serde(default)]
+    pub damping_y: f32,
+    pub view: ViewSettings,
+    pub dye_color: [u8; 3],
+    pub show_lic: bool,
+    pub show_stream_function: bool,
+    pub show_shear: bool,
+    pub lic_kernel_length: usize,
+    pub smooth_render: bool,
+    #[serde(default)]
+    pub render_stride: Option<usize>,
+    pub arrow_scale: f32,
+    pub auto_arrow_scale: bool,
+    pub ghost_boundaries: bool,
+    // Whether the scene runs a refined patch around the obstacle, and its factor and size
+    #[serde(default)]
+    pub refinement: bool,
+    pub refinement_factor: usize,
+    pub refinement_size: usize,
+    // Painted porous resistance and forced velocities per cell, empty when nothing is painted
+    #[serde(default)]
+    pub resistance: Vec<f32>,
+    #[serde(default)]
+    pub forced: Vec<Option<[f32; 2]>>,
+}
+
+// Fields of a Fluid carried from one step to the next
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FluidState {
+    pub num_x: usize,
+    pub num_y: usize,
+    pub h: f32,
+    pub density: f32,
+    pub u: Vec<f32>,
+    pub v: Vec<f32>,
+    pub p: Vec<f32>,
+    pub s: Vec<f32>,
+    pub m: Vec<f32>,
+    pub resistance: Vec<f32>,
+    pub forced: Vec<Option<[f32; 2]>>,
+}
+
+// Settings together with the flow, restoring it continues the simulation exactly where it was
+// saved. Not part of it are a force field set from code, the rewind history, the wake samples
+// behind the shedding frequency and the diagnostics, which all start over
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SceneSnapshot {
+    pub settings: SceneSettings,
+    pub frame_nr: usize,
+    pub sim_time: f32,
+    // Frame time advance hadn't simulated yet
+    #[serde(default)]
+    pub time_accumulator: f32,
+    pub obstacle_angle: f32,
+    pub fluid: FluidState,
+    // Flow in the refinement patch, None when the scene runs without one
+    #[serde(default)]
+    pub refinement: Option<FluidState>,
+}
+
+impl SceneSnapshot {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+impl SceneSettings {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        std::fs::write(path, self.to_json()?)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+// Round region of the grid that holds fluid, every cell outside is solid and left undrawn
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct CircularDomain {
+    // Center in simulation coordinates
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+impl CircularDomain {
+    pub fn contains(&self, pos: Vec2) -> bool {
+        (pos - self.center).length_squared() < self.radius * self.radius
+    }
+}
+
+// Colors used around the simulated fields: the empty background, solid cells and overlays
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct FluidTheme {
+    pub name: &'static str,
+    pub dark: bool,
+    pub background: Srgba,
+    pub solid: Srgba,
+    pub gizmo: Srgba,
+    // Gizmo color used on top of dark fields, such as pressure darkened by smoke
+    pub gizmo_contrast: Srgba,
+    pub text: Srgba,
+}
+
+impl FluidTheme {
+    pub const LIGHT: FluidTheme = FluidTheme {
+        name: "Light",
+        dark: false,
+        background: Srgba::new(1.0, 1.0, 1.0, 1.0),
+        solid: Srgba::new(0.0, 0.0, 0.0, 1.0),
+        gizmo: Srgba::new(0.0, 0.0, 0.0, 1.0),
+        gizmo_contrast: Srgba::new(1.0, 1.0, 1.0, 1.0),
+        text: Srgba::new(0.1, 0.1, 0.1, 1.0),
+    };
+
+    pub const DARK: FluidTheme = FluidTheme {
+        name: "Dark",
+        dark: true,
+        background: Srgba::new(0.08, 0.08, 0.1, 1.0),
+        solid: Srgba::new(0.35, 0.35, 0.4, 1.0),
+        gizmo: Srgba::new(0.9, 0.9, 0.9, 1.0),
+        gizmo_contrast: Srgba::new(1.0, 1.0, 1.0, 1.0),
+        text: Srgba::new(0.9, 0.9, 0.9, 1.0),
+    };
+
+    pub const ALL: [FluidTheme; 2] = [FluidTheme::LIGHT, FluidTheme::DARK];
+
+    fn rgb255(color: Srgba) -> [f32; 3] {
+        [255.0 * color.red, 255.0 * color.green, 255.0 * color.blue]
+    }
+}
+
+impl Default for FluidTheme {
+    fn default() -> Self {
+        FluidTheme::LIGHT
+    }
 }
 
 #[derive(Component)]
 pub struct FluidScene {
     pub gravity: f32,
     pub dt: f32,
+    // Substep length used by advance
+    pub fixed_dt: f32,
+    // Step length the app uses instead of the frame time, so runs don't depend on frame timing.
+    // Tests and tools can instead call step or advance with their own dt
+    pub manual_dt: Option<f32>,
+    // Wall clock time advance hasn't simulated yet
+    time_accumulator: f32,
     pub num_iters: usize,
     pub frame_nr: usize,
-    pub over_relaxation: f32,
-    pub obstacle_pos: Vec2,
-    pub obstacle_radius: f32,
+    // Physical time simulated so far, in seconds
+    sim_time: f32,
+    // Kept within [1, MAX_OVERRELAXATION] by set_over_relaxation
+    over_relaxation: f32,
+    // Lowers the over-relaxation near solids, see Fluid::set_relaxation_from_solids
+    local_relaxation: bool,
+    pub time_integration: TimeIntegration,
+    // Averages the velocity with its mirror image after every projection
+    pub enforce_symmetry: Option<SymmetryAxis>,
+    force_field: Option<ForceField>,
+    // 0 uses the raw drag velocity, values towards 1 smooth more
+    pub obstacle_smoothing: f32,
+    // Hard cap on the drag velocity in m/s, guarding the solver against violent flicks
+    pub max_obstacle_speed: f32,
+    // Vorticity confinement strength, 0 turns it off. Brings back the small vortices the
+    // semi-Lagrangian advection smears out
+    pub vorticity_strength: f32,
+    // Kinematic viscosity in m^2/s, 0 leaves only the numerical diffusion of the advection
+    pub viscosity: f32,
+    // When the drag velocity was last clamped to max_obstacle_speed
+    obstacle_speed_clamped: Option<Instant>,
+    // Obstacle ids are indices into this. The first is the draggable obstacle, which spins, can be
+    // resized and is the one the wake, forces and refinement patch follow, a radius of 0 removes
+    // it. The rest are fixed and only move when dragged. Velocities are the smoothed drag velocity
+    obstacles: Vec<Obstacle>,
+    // Strength of the Tank's bottom plume source in 1/s, 0 turns it off
+    pub tank_source_rate: f32,
+    // Speed fluid leaves through the Tank's bottom in m/s, 0 keeps the bottom closed. The open top
+    // lets the same amount back in, so the tank runs as a continuous through-flow
+    pub tank_drain_speed: f32,
+    // Time over which the inlet velocity eases in from rest, 0 starts at full speed
+    pub inflow_ramp_seconds: f32,
+    // Inlet speed for every row of the first fluid column, None blows uniformly
+    inflow_profile: Option<Vec<f32>>,
+    pub wall_smoke_mode: WallSmokeMode,
+    // Upper bound of the amount of dye 1 - m, with 1 the clear value of m. Colors are normalized to it
+    pub max_smoke: f32,
+    // Rate in 1/s at which smoke fades back to the background, 0 keeps trails forever
+    pub trail_decay: f32,
+    // Width in cells of the band outside the obstacle eased towards its surface velocity
+    pub obstacle_feather: f32,
+    // Sub-points per cell side tested against the obstacle circle, a cell is solid when at least
+    // half of them are inside. 1 tests only the cell center
+    pub obstacle_subsamples: usize,
+    // Spin of the obstacle in radians per second, counterclockwise
+    pub obstacle_rotation_rate: f32,
+    obstacle_angle: f32,
     pub show_streamlines: bool,
     pub show_velocities: bool,
     pub show_pressure: bool,
     pub show_smoke: bool,
     pub show_smoke_gradient: bool,
+    pub colormap: ColorMap,
+    // Color of dense smoke in the plain smoke view, thinning out to white
+    pub dye_color: [u8; 3],
+    pub show_courant: bool,
+    // Draws the solid mask alone, white for fluid and black for solid
+    pub show_mask: bool,
+    // Line integral convolution of the velocity field, drawn into its own image
+    pub show_lic: bool,
+    // Contours of the stream function, which are exact streamlines even around recirculation
+    pub show_stream_function: bool,
+    // Colors fluid cells by strain rate magnitude, which picks out boundary and mixing layers
+    pub show_shear: bool,
+    // Half cell steps taken each way along the streamline when convolving
+    pub lic_kernel_length: usize,
+    pub smooth_render: bool,
+    // Cells per drawn block side when drawing cell blocks, None picks it from the grid to image
+    // ratio so fine grids don't write each pixel many times
+    pub render_stride: Option<usize>,
+    // Length of velocity arrows per unit of velocity, in simulation units
+    pub arrow_scale: f32,
+    // Scale arrows so the longest one spans a single cell
+    pub auto_arrow_scale: bool,
+    // Part of the domain, in simulation coordinates, stretched over the whole image. None draws
+    // the full domain.
+    pub render_region: Option<Rect>,
+    pub ghost_boundaries: bool,
+    // Width in cells of the band along the domain walls where velocity is relaxed towards rest,
+    // 0 turns it off. Open edges and the inlet are left alone
+    pub clamp_border: usize,
+    pub render_format: RenderFormat,
+    pub inlet_source: InletSource,
+    // Re-applies the inlet smoke every step for continuous streaklines, otherwise it is only
+    // released when the scene is set up and washes downstream
+    pub continuous_inlet_smoke: bool,
+    // Value m is filled with when the scene is created, 1 is clear and 0 full of smoke
+    initial_smoke: f32,
+    // Height of the band inlet source as a fraction of the domain height
+    pub inlet_band_fraction: f32,
+    pub theme: FluidTheme,
+    // Cell row the inlet smoke is centered on
+    inlet_center: f32,
 
     pub scale: f32,
     pub width: f32,
     pub height: f32,
 
     pub fluid: Fluid,
+    pub collect_diagnostics: bool,
+    pub histogram_bins: usize,
+    pub record_residuals: bool,
+    // Solver iteration counts the next iteration comparison projects the same field with
+    pub comparison_iterations: Vec<usize>,
+    compare_iterations: bool,
+    iteration_comparison: Option<IterationComparison>,
+    // Stops the flow in fluid pockets cut off from the main region, which no pressure solve can
+    // connect to the rest of the domain
+    pub clear_trapped_pockets: bool,
+    pub profile: bool,
+    pub timings: StepTimings,
+    pub diagnostics: Diagnostics,
+    pub refinement: Option<RefinementPatch>,
+    pub refinement_factor: usize,
+    // Coarse cells per side covered by the refinement patch
+    pub refinement_size: usize,
+    // Solid mask of the scene geometry without the obstacle
+    base_s: Vec<f32>,
+    circular_domain: Option<CircularDomain>,
+    // Transverse velocity in the wake as (sim time, v), oldest first
+    wake_history: VecDeque<(f32, f32)>,
+    // Stops the simulation, step_forward advances a single frame while paused
+    pub paused: bool,
+    step_forward: bool,
+    // Only redraws the fixed flow, unlike pause nothing can advance or edit it, for composing captures
+    pub frozen: bool,
+    // Recent states, newest last. Recording is opt-in, the default depth of 0 disables it
+    pub history_depth: usize,
+    history: VecDeque<HistoryEntry>,
 
     pub scene_type: SceneType,
 
@@ -57,30 +828,112 @@ impl FluidScene {
             SceneType::Tank => 50.0,
             _ => 100.0,
         };
+        Self::with_resolution(width, height, scene_type, resolution)
+    }
 
+    // Scene with the given number of cells across the domain height, drawn into a width x height image
+    pub fn with_resolution(width: f32, height: f32, scene_type: SceneType, resolution: f32) -> Self {
         let domain_height = SIM_HEIGHT;
         let domain_width = domain_height / height * width;
         let h = domain_height / resolution;
         let num_cells_x = f32::floor(domain_width / h) as usize + 2;
         let num_cells_y = f32::floor(domain_height / h) as usize + 2;
+        Self::with_grid(width, height, scene_type, (num_cells_x, num_cells_y), h, height / domain_height)
+    }
 
+    // Scene simulated on num_x x num_y interior cells spanning the domain height, drawn into a
+    // width x height image. The grid is scaled to fit the image when the aspect ratios differ
+    pub fn with_domain(width: f32, height: f32, scene_type: SceneType, num_x: usize, num_y: usize) -> Self {
+        let h = SIM_HEIGHT / num_y as f32;
+        let scale = f32::min(width / (num_x as f32 * h), height / SIM_HEIGHT);
+        Self::with_grid(width, height, scene_type, (num_x + 2, num_y + 2), h, scale)
+    }
+
+    // Grid size includes the boundary cells, scale is image pixels per simulation unit
+    fn with_grid(width: f32, height: f32, scene_type: SceneType, (num_cells_x, num_cells_y): (usize, usize), h: f32, scale: f32) -> Self {
         let mut scene = FluidScene {
             gravity: GRAVITY,
             dt: TIMESTEP,
+            fixed_dt: TIMESTEP,
+            manual_dt: None,
+            time_accumulator: 0.0,
             num_iters: NUMBER_ITERATIONS,
             frame_nr: 0,
+            sim_time: 0.0,
             over_relaxation: OVERRELAXATION,
-            obstacle_pos: OBSTACLE_ZERO,
-            obstacle_radius: OBSTACLE_RADIUS,
+            local_relaxation: false,
+            time_integration: TimeIntegration::Euler,
+            enforce_symmetry: None,
+            force_field: None,
+            obstacle_smoothing: 0.0,
+            max_obstacle_speed: MAX_OBSTACLE_SPEED,
+            vorticity_strength: 0.0,
+            viscosity: 0.0,
+            obstacle_speed_clamped: None,
+            obstacles: vec![Obstacle { pos: OBSTACLE_ZERO, radius: OBSTACLE_RADIUS, vel: Vec2::ZERO }],
+            tank_source_rate: 0.0,
+            tank_drain_speed: 0.0,
+            inflow_ramp_seconds: 0.0,
+            inflow_profile: None,
+            wall_smoke_mode: WallSmokeMode::Absorb,
+            max_smoke: MAX_SMOKE,
+            trail_decay: 0.0,
+            obstacle_feather: 0.0,
+            obstacle_subsamples: OBSTACLE_SUBSAMPLES,
+            obstacle_rotation_rate: 0.0,
+            obstacle_angle: 0.0,
             show_streamlines: false,
             show_velocities: false,
             show_pressure: false,
             show_smoke: true,
             show_smoke_gradient: false,
-            scale: height / domain_height,
+            colormap: ColorMap::default(),
+            dye_color: [0, 0, 0],
+            show_courant: false,
+            show_mask: false,
+            show_lic: false,
+            show_stream_function: false,
+            show_shear: false,
+            lic_kernel_length: LIC_KERNEL_LENGTH,
+            smooth_render: false,
+            render_stride: None,
+            arrow_scale: ARROW_SCALE,
+            auto_arrow_scale: false,
+            render_region: None,
+            ghost_boundaries: false,
+            clamp_border: 0,
+            render_format: RenderFormat::Rgba,
+            inlet_source: InletSource::Band,
+            theme: FluidTheme::default(),
+            inlet_center: 0.0,
+            continuous_inlet_smoke: true,
+            initial_smoke: 1.0,
+            inlet_band_fraction: 0.1,
+            scale,
             width,
             height,
             fluid: Fluid::new(DENSITY, num_cells_x, num_cells_y, h),
+            collect_diagnostics: false,
+            histogram_bins: HISTOGRAM_BINS,
+            record_residuals: false,
+            comparison_iterations: vec![5, 20, 100],
+            compare_iterations: false,
+            iteration_comparison: None,
+            clear_trapped_pockets: false,
+            profile: false,
+            timings: StepTimings::default(),
+            diagnostics: Diagnostics::default(),
+            refinement: None,
+            refinement_factor: REFINEMENT_FACTOR,
+            refinement_size: REFINEMENT_SIZE,
+            base_s: Vec::new(),
+            circular_domain: None,
+            wake_history: VecDeque::new(),
+            paused: false,
+            step_forward: false,
+            frozen: false,
+            history_depth: 0,
+            history: VecDeque::new(),
             scene_type,
             image_handle: Handle::default(),
         };
@@ -90,133 +943,1464 @@ impl FluidScene {
             SceneType::HiresTunnel => scene.setup_tunnel(SceneType::HiresTunnel),
             SceneType::Tank => scene.setup_tank(),
             SceneType::Paint => scene.setup_paint(),
+            SceneType::BackwardStep => scene.setup_backward_step(),
+            SceneType::Coanda => scene.setup_coanda(),
         };
+        scene.apply_view_settings(ViewSettings::scene_default(scene_type));
+        scene.base_s = scene.fluid.s.clone();
 
         scene
     }
 
-    pub fn step(&mut self, dt: f32, render_buffer: &mut [u8]) {
-        self.dt = dt;
-
-        self.fluid.integrate(dt, self.gravity);
-        self.fluid.p.fill(0.0);
+    // Rebuilds the scene described by settings, with the flow starting from rest
+    pub fn from_settings(settings: &SceneSettings) -> io::Result<Self> {
+        let [num_x, num_y] = settings.cells;
+        if num_x == 0 || num_y == 0 || settings.cell_size <= 0.0 || settings.scale <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the grid of the scene settings is empty"));
+        }
+        let mut scene = Self::with_grid(settings.width, settings.height, settings.scene_type, (num_x + 2, num_y + 2), settings.cell_size, settings.scale);
 
-        self.fluid.solve_incompressibility(dt, self.num_iters as i32, self.over_relaxation);
+        scene.gravity = settings.gravity;
+        scene.fixed_dt = settings.fixed_dt;
+        scene.manual_dt = settings.manual_dt;
+        scene.num_iters = settings.num_iters;
+        scene.set_over_relaxation(settings.over_relaxation);
+        scene.set_local_relaxation(settings.local_relaxation);
+        scene.time_integration = settings.time_integration;
+        scene.enforce_symmetry = settings.enforce_symmetry;
+        scene.obstacle_smoothing = settings.obstacle_smoothing;
+        scene.max_obstacle_speed = settings.max_obstacle_speed;
+        scene.vorticity_strength = settings.vorticity_strength;
+        scene.viscosity = settings.viscosity;
+        scene.obstacle_feather = settings.obstacle_feather;
+        scene.obstacle_subsamples = settings.obstacle_subsamples;
+        scene.tank_source_rate = settings.tank_source_rate;
+        scene.tank_drain_speed = settings.tank_drain_speed;
+        scene.inflow_ramp_seconds = settings.inflow_ramp_seconds;
+        if let Some(profile) = &settings.inflow_profile {
+            scene.set_inflow_profile(profile);
+        }
+        scene.inlet_source = settings.inlet_source;
+        scene.continuous_inlet_smoke = settings.continuous_inlet_smoke;
+        scene.inlet_band_fraction = settings.inlet_band_fraction;
+        scene.wall_smoke_mode = settings.wall_smoke_mode;
+        scene.max_smoke = settings.max_smoke;
+        scene.trail_decay = settings.trail_decay;
+        scene.clamp_border = settings.clamp_border;
+        scene.fluid.damping_x = settings.damping_x;
+        scene.fluid.damping_y = settings.damping_y;
+        scene.apply_view_settings(settings.view);
+        scene.dye_color = settings.dye_color;
+        scene.show_lic = settings.show_lic;
+        scene.show_stream_function = settings.show_stream_function;
+        scene.show_shear = settings.show_shear;
+        scene.lic_kernel_length = settings.lic_kernel_length;
+        scene.smooth_render = settings.smooth_render;
+        scene.render_stride = settings.render_stride;
+        scene.arrow_scale = settings.arrow_scale;
+        scene.auto_arrow_scale = settings.auto_arrow_scale;
+        scene.ghost_boundaries = settings.ghost_boundaries;
+        scene.refinement_factor = settings.refinement_factor;
+        scene.refinement_size = settings.refinement_size;
 
-        self.fluid.extrapolate();
-        self.fluid.advect_vel(dt);
-        self.fluid.advect_smoke(dt);
+        let cells = scene.fluid.num_cells;
+        if ![0, cells].contains(&settings.resistance.len()) || ![0, cells].contains(&settings.forced.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the painted regions don't match the grid of the scene settings"));
+        }
+        if !settings.resistance.is_empty() {
+            scene.fluid.resistance.copy_from_slice(&settings.resistance);
+        }
+        for (forced, saved) in scene.fluid.forced.iter_mut().zip(&settings.forced) {
+            *forced = saved.map(Vec2::from_array);
+        }
 
-        self.frame_nr += 1;
+        if let Some((center, radius)) = settings.circular_domain {
+            scene.set_circular_domain(Some(CircularDomain { center: Vec2::from_array(center), radius }));
+        }
+        scene.set_initial_smoke(settings.initial_smoke);
+        scene.apply_obstacle_layout(&settings.obstacles)?;
+        // The patch is placed around the obstacle, so it's built once that is in place
+        scene.set_refinement(settings.refinement);
 
-        self.draw(render_buffer);
+        Ok(scene)
     }
 
-    fn setup_tank(&mut self) {
-        self.scene_type = SceneType::Tank;
+    // Rebuilds the scene from its settings, then puts the saved flow back in place
+    pub fn from_snapshot(snapshot: &SceneSnapshot) -> io::Result<Self> {
+        let mut scene = Self::from_settings(&snapshot.settings)?;
+        scene.fluid.restore(&snapshot.fluid)?;
+        // The patch was placed by from_settings, only its flow is left to put back
+        if let (Some(patch), Some(state)) = (scene.refinement.as_mut(), &snapshot.refinement) {
+            patch.fluid.restore(state)?;
+        }
+        scene.frame_nr = snapshot.frame_nr;
+        scene.sim_time = snapshot.sim_time;
+        scene.time_accumulator = snapshot.time_accumulator;
+        scene.obstacle_angle = snapshot.obstacle_angle;
+        Ok(scene)
+    }
 
-        let fluid = &mut self.fluid;
-        let n = fluid.num_y;
-        for i in 0..fluid.num_x {
-            for j in 0..fluid.num_y {
-                let mut s = 1.0; // fluid
-                if i == 0 || i == fluid.num_x - 1 || j == 0 {
-                    s = 0.0; // solid
-                }
-                fluid.s[i * n + j] = s;
-            }
+    pub fn snapshot(&self) -> SceneSnapshot {
+        SceneSnapshot {
+            settings: self.settings(),
+            frame_nr: self.frame_nr,
+            sim_time: self.sim_time,
+            time_accumulator: self.time_accumulator,
+            obstacle_angle: self.obstacle_angle,
+            fluid: self.fluid.state(),
+            refinement: self.refinement.as_ref().map(|patch| patch.fluid.state()),
         }
+    }
 
-        self.gravity = -9.81;
+    pub fn settings(&self) -> SceneSettings {
+        SceneSettings {
+            scene_type: self.scene_type,
+            width: self.width,
+            height: self.height,
+            cells: [self.fluid.num_x - 2, self.fluid.num_y - 2],
+            cell_size: self.fluid.h,
+            scale: self.scale,
+            gravity: self.gravity,
+            fixed_dt: self.fixed_dt,
+            manual_dt: self.manual_dt,
+            num_iters: self.num_iters,
+            over_relaxation: self.over_relaxation,
+            local_relaxation: self.local_relaxation,
+            time_integration: self.time_integration,
+            enforce_symmetry: self.enforce_symmetry,
+            obstacles: self.obstacle_layout(),
+            obstacle_smoothing: self.obstacle_smoothing,
+            max_obstacle_speed: self.max_obstacle_speed,
+            vorticity_strength: self.vorticity_strength,
+            viscosity: self.viscosity,
+            obstacle_feather: self.obstacle_feather,
+            obstacle_subsamples: self.obstacle_subsamples,
+            circular_domain: self.circular_domain.map(|domain| (domain.center.to_array(), domain.radius)),
+            tank_source_rate: self.tank_source_rate,
+            tank_drain_speed: self.tank_drain_speed,
+            inflow_ramp_seconds: self.inflow_ramp_seconds,
+            inflow_profile: self.inflow_profile.clone(),
+            inlet_source: self.inlet_source,
+            continuous_inlet_smoke: self.continuous_inlet_smoke,
+            inlet_band_fraction: self.inlet_band_fraction,
+            initial_smoke: self.initial_smoke,
+            wall_smoke_mode: self.wall_smoke_mode,
+            max_smoke: self.max_smoke,
+            trail_decay: self.trail_decay,
+            clamp_border: self.clamp_border,
+            damping_x: self.fluid.damping_x,
+            damping_y: self.fluid.damping_y,
+            view: self.view_settings(),
+            dye_color: self.dye_color,
+            show_lic: self.show_lic,
+            show_stream_function: self.show_stream_function,
+            show_shear: self.show_shear,
+            lic_kernel_length: self.lic_kernel_length,
+            smooth_render: self.smooth_render,
+            render_stride: self.render_stride,
+            arrow_scale: self.arrow_scale,
+            auto_arrow_scale: self.auto_arrow_scale,
+            ghost_boundaries: self.ghost_boundaries,
+            refinement: self.refinement.is_some(),
+            refinement_factor: self.refinement_factor,
+            refinement_size: self.refinement_size,
+            resistance: if self.fluid.resistance.iter().any(|&r| r != 0.0) { self.fluid.resistance.clone() } else { Vec::new() },
+            forced: if self.fluid.forced.iter().any(Option::is_some) {
+                self.fluid.forced.iter().map(|forced| forced.map(|velocity| velocity.to_array())).collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
 
-        self.show_pressure = true;
-        self.show_smoke = false;
-        self.show_streamlines = false;
-        self.show_velocities = false;
+    // Length of the app's next step, manual_dt when set and otherwise the frame time
+    pub fn frame_dt(&self, frame_seconds: f32) -> f32 {
+        self.manual_dt.unwrap_or(frame_seconds)
     }
 
-    fn setup_tunnel(&mut self, scene_type: SceneType) {
-        self.scene_type = scene_type;
+    // Runs one step of dt and draws the result. Paused and frozen scenes are still redrawn so view
+    // changes show up
+    pub fn step(&mut self, dt: f32, render_buffer: &mut [u8]) {
+        self.step_simulation(dt);
 
-        let fluid = &mut self.fluid;
-        let n = fluid.num_y;
-        for i in 0..fluid.num_x {
-            for j in 0..fluid.num_y {
-                let mut s = 1.0; // fluid
-                if i == 0 || j == 0 || j == fluid.num_y - 1 {
-                    s = 0.0; //solid
-                }
-                fluid.s[i * n + j] = s;
-                if i == 1 {
-                    fluid.u[i * n + j] = VELOCITY_IN;
-                }
+        let mut clock = self.profile.then(Instant::now);
+        self.draw(render_buffer);
+        lap(&mut clock, &mut self.timings.draw);
+    }
+
+    // Advances by a wall clock duration in fixed_dt substeps, carrying the remainder over to the
+    // next call, and draws once at the end when given a buffer. Returns the substeps taken
+    pub fn advance(&mut self, real_seconds: f32, render_buffer: Option<&mut [u8]>) -> usize {
+        self.time_accumulator += real_seconds.max(0.0);
+        let mut substeps = 0;
+        while self.time_accumulator >= self.fixed_dt && substeps < MAX_SUBSTEPS {
+            self.time_accumulator -= self.fixed_dt;
+            if !self.step_simulation(self.fixed_dt) {
+                // Time doesn't pile up while paused
+                self.time_accumulator = 0.0;
+                break;
             }
+            substeps += 1;
         }
-
-        let pipe_height = 0.1 * fluid.num_y as f32;
-        let min_j = f32::floor(0.5 * fluid.num_y as f32 - 0.5 * pipe_height) as usize;
-        let max_j = f32::floor(0.5 * fluid.num_y as f32 + 0.5 * pipe_height) as usize;
-
-        for j in min_j..max_j {
-            fluid.m[j] = 0.0; // solid
+        // Drop a backlog the solver can't catch up with rather than falling further behind
+        if substeps == MAX_SUBSTEPS {
+            self.time_accumulator = 0.0;
         }
 
-        self.gravity = 0.0;
-
-        self.show_smoke = true;
-
-        if scene_type == SceneType::HiresTunnel {
-            self.dt = 1.0 / 120.0;
-            self.num_iters = 100;
-
-            self.show_pressure = true;
+        if let Some(render_buffer) = render_buffer {
+            self.draw(render_buffer);
         }
+        substeps
     }
 
-    fn setup_paint(&mut self) {
-        self.scene_type = SceneType::Paint;
-
-        self.gravity = 0.0;
-        self.over_relaxation = 1.0;
-        self.obstacle_radius = 0.05;
+    // The physics phases of a step without drawing, so scenes can run headless. Skipped while
+    // paused or frozen, returns whether they ran
+    pub fn step_simulation(&mut self, dt: f32) -> bool {
+        if self.frozen || (self.paused && !self.step_forward) {
+            return false;
+        }
+        self.step_forward = false;
+        self.record_history();
 
-        self.frame_nr = 0;
+        self.dt = dt;
 
-        self.show_smoke = true;
-        self.show_smoke_gradient = true;
-        self.show_pressure = false;
-    }
+        if self.obstacle_rotation_rate != 0.0 && self.obstacles[0].radius > 0.0 {
+            self.obstacle_angle = (self.obstacle_angle + self.obstacle_rotation_rate * dt) % std::f32::consts::TAU;
+            self.rasterize_obstacles();
+        }
 
-    pub fn set_obstacle(&mut self, pos: Vec2, reset: bool) {
-        if pos.x < 0.2 || pos.x > (self.width * 0.01) - 0.1 || pos.y < 0.1 || pos.y > (self.height * 0.01) - 0.1 {
-            return;
+        if self.has_inflow() {
+            self.apply_inflow();
+        }
+        if self.scene_type == SceneType::Tank && self.tank_drain_speed > 0.0 {
+            self.apply_tank_drain();
         }
-        let fluid = &mut self.fluid;
 
-        let mut v = Vec2::ZERO;
+        let mut clock = self.profile.then(Instant::now);
 
-        if !reset {
-            v = (pos - self.obstacle_pos) / self.dt;
+        self.fluid.integrate(dt, self.gravity, self.time_integration);
+        if let Some(force_field) = self.force_field.as_mut() {
+            self.fluid.apply_force_field(dt, force_field);
+        }
+        // Applied as a body force so the projection removes the divergence it adds
+        if self.vorticity_strength > 0.0 {
+            self.fluid.apply_vorticity_confinement(dt, self.vorticity_strength);
         }
+        if self.viscosity > 0.0 {
+            self.fluid.diffuse_velocity(dt, self.viscosity);
+        }
+        lap(&mut clock, &mut self.timings.integrate);
 
-        self.obstacle_pos = pos;
-        let r = self.obstacle_radius;
-        let n = fluid.num_y;
-        let h = fluid.h;
+        if self.compare_iterations {
+            self.compare_iterations = false;
+            self.compare_iteration_counts(dt);
+        }
 
-        for i in 1..fluid.num_x - 2 {
-            for j in 1..fluid.num_y - 2 {
-                fluid.s[i * n + j] = 1.0;
-                let dx = (i as f32 + 0.5) * h - pos.x;
-                let dy = (j as f32 + 0.5) * h - pos.y;
+        self.fluid.p.fill(0.0);
+        if self.local_relaxation {
+            self.fluid.set_relaxation_from_solids(self.over_relaxation);
+        }
+        if !self.record_residuals {
+            // Don't leave a stale history behind for residual_growing
+            self.diagnostics.residual_history.clear();
+        }
+        let residuals = self.record_residuals.then_some(&mut self.diagnostics.residual_history);
+        self.fluid.solve_incompressibility(dt, self.num_iters as i32, self.over_relaxation, residuals);
+        if let Some(axis) = self.enforce_symmetry {
+            self.fluid.symmetrize(axis);
+        }
+        lap(&mut clock, &mut self.timings.projection);
+
+        if self.collect_diagnostics || self.clear_trapped_pockets {
+            let (labels, regions) = self.fluid.label_fluid_regions();
+            if regions > 1 && regions > self.diagnostics.fluid_regions {
+                warn!("The fluid is split into {} disconnected regions", regions);
+            }
+            self.diagnostics.fluid_regions = regions;
+            if self.clear_trapped_pockets && regions > 1 {
+                self.fluid.clear_all_but_largest_region(&labels, regions);
+            }
+        }
+        if self.collect_diagnostics {
+            self.diagnostics.max_divergence = self.fluid.max_divergence();
+            self.diagnostics.divergence_histogram = self.fluid.divergence_histogram(self.histogram_bins);
+            // Measurement work isn't part of any stage
+            clock = clock.map(|_| Instant::now());
+        }
+
+        self.fluid.apply_forced_velocities();
+        self.fluid.extrapolate();
+        if self.ghost_boundaries {
+            self.fluid.apply_ghost_boundaries();
+        }
+        lap(&mut clock, &mut self.timings.extrapolate);
+
+        self.fluid.advect_vel(dt);
+        if self.clamp_border > 0 {
+            self.relax_border(dt);
+        }
+        lap(&mut clock, &mut self.timings.advect_vel);
+
+        self.fluid.advect_smoke(dt, self.wall_smoke_mode);
+        if self.trail_decay > 0.0 {
+            self.fluid.decay_smoke(f32::exp(-self.trail_decay * dt));
+        }
+        if self.has_inflow() && self.continuous_inlet_smoke {
+            self.inject_inlet_smoke();
+        }
+        if self.scene_type == SceneType::Tank && self.tank_source_rate > 0.0 {
+            self.apply_tank_source(dt);
+        }
+        self.fluid.clamp_smoke(self.max_smoke);
+        lap(&mut clock, &mut self.timings.advect_smoke);
+
+        if let Some(patch) = self.refinement.as_mut() {
+            patch.step(&mut self.fluid, dt, self.gravity, self.time_integration, self.num_iters, self.over_relaxation);
+        }
+        lap(&mut clock, &mut self.timings.refinement);
+
+        self.frame_nr += 1;
+        self.sim_time += dt;
+        self.record_wake();
+        true
+    }
+
+    pub fn view_settings(&self) -> ViewSettings {
+        ViewSettings {
+            show_streamlines: self.show_streamlines,
+            show_velocities: self.show_velocities,
+            show_pressure: self.show_pressure,
+            show_smoke: self.show_smoke,
+            show_smoke_gradient: self.show_smoke_gradient,
+            show_courant: self.show_courant,
+            show_mask: self.show_mask,
+            colormap: self.colormap,
+        }
+    }
+
+    pub fn apply_view_settings(&mut self, view: ViewSettings) {
+        self.show_streamlines = view.show_streamlines;
+        self.show_velocities = view.show_velocities;
+        self.show_pressure = view.show_pressure;
+        self.show_smoke = view.show_smoke;
+        self.show_smoke_gradient = view.show_smoke_gradient;
+        self.show_courant = view.show_courant;
+        self.show_mask = view.show_mask;
+        self.colormap = view.colormap;
+    }
+
+    pub fn sim_time(&self) -> f32 {
+        self.sim_time
+    }
+
+    // False when the fields hold NaN or infinite values or the flow is running away
+    pub fn is_stable(&self) -> bool {
+        let fluid = &self.fluid;
+        let finite = fluid.u.iter().chain(fluid.v.iter()).chain(fluid.p.iter()).all(|value| value.is_finite());
+        if !finite {
+            return false;
+        }
+        let max_velocity = fluid.u.iter().chain(fluid.v.iter()).fold(0.0, |max: f32, v| max.max(v.abs()));
+        max_velocity <= STABLE_MAX_VELOCITY && fluid.max_courant(self.dt) <= STABLE_MAX_COURANT
+    }
+
+    // Solid mask as a binary PGM image, one pixel per cell with the top row first
+    pub fn mask_pgm(&self) -> Vec<u8> {
+        let fluid = &self.fluid;
+        let n = fluid.num_y;
+        let mut pgm = format!("P5\n{} {}\n255\n", fluid.num_x, fluid.num_y).into_bytes();
+        for j in (0..fluid.num_y).rev() {
+            for i in 0..fluid.num_x {
+                pgm.push(if fluid.s[i * n + j] == 0.0 { 0 } else { 255 });
+            }
+        }
+        pgm
+    }
+
+    // Arrow length per unit of velocity for the velocity overlay
+    pub fn effective_arrow_scale(&self) -> f32 {
+        if !self.auto_arrow_scale {
+            return self.arrow_scale;
+        }
+        let fluid = &self.fluid;
+        let max_velocity = fluid.u.iter().chain(fluid.v.iter()).fold(0.0, |max: f32, v| max.max(v.abs()));
+        if max_velocity > 0.0 { fluid.h / max_velocity } else { self.arrow_scale }
+    }
+
+    // Projects copies of the next step's pre-projection velocities once per configured count
+    pub fn request_iteration_comparison(&mut self) {
+        self.compare_iterations = true;
+    }
+
+    pub fn iteration_comparison(&self) -> Option<&IterationComparison> {
+        self.iteration_comparison.as_ref()
+    }
+
+    fn compare_iteration_counts(&mut self, dt: f32) {
+        let fields = self.comparison_iterations.iter().map(|&iterations| {
+            let mut fluid = self.fluid.clone();
+            fluid.p.fill(0.0);
+            fluid.solve_incompressibility(dt, iterations as i32, self.over_relaxation, None);
+            (iterations, fluid.divergence_field())
+        }).collect();
+        self.iteration_comparison = Some(IterationComparison { frame_nr: self.frame_nr, fields });
+    }
+
+    pub fn over_relaxation(&self) -> f32 {
+        self.over_relaxation
+    }
+
+    pub fn set_over_relaxation(&mut self, over_relaxation: f32) {
+        self.over_relaxation = over_relaxation.clamp(1.0, MAX_OVERRELAXATION);
+    }
+
+    pub fn local_relaxation(&self) -> bool {
+        self.local_relaxation
+    }
+
+    // Switches between a per cell relaxation derived from the solids and the scalar factor
+    pub fn set_local_relaxation(&mut self, enabled: bool) {
+        self.local_relaxation = enabled;
+        if !enabled {
+            self.fluid.relaxation = None;
+        }
+    }
+
+    // Extra body force added to the velocity together with gravity, before the projection
+    // removes its divergent part. None removes it.
+    pub fn set_force_field(&mut self, force_field: Option<ForceField>) {
+        self.force_field = force_field;
+    }
+
+    pub fn has_force_field(&self) -> bool {
+        self.force_field.is_some()
+    }
+
+    // Current orientation of the obstacle in radians
+    pub fn obstacle_angle(&self) -> f32 {
+        self.obstacle_angle
+    }
+
+    pub fn circular_domain(&self) -> Option<CircularDomain> {
+        self.circular_domain
+    }
+
+    // World coordinates are relative to the center of the scene's sprite
+    pub fn world_to_pos(&self, world: Vec2) -> Vec2 {
+        Vec2::new(
+            (world.x + (self.width + 3.) / 2.) / self.scale,
+            (world.y + (self.height - 1.) / 2.) / self.scale,
+        )
+    }
+
+    // Fields at a world coordinate, points outside of the domain read the nearest cell
+    pub fn probe(&self, world: Vec2) -> FluidSample {
+        let fluid = &self.fluid;
+        let h = fluid.h;
+        let pos = self.world_to_pos(world);
+        let i = ((pos.x / h).max(0.0) as usize).min(fluid.num_x - 1);
+        let j = ((pos.y / h).max(0.0) as usize).min(fluid.num_y - 1);
+        FluidSample {
+            velocity: Vec2::new(
+                fluid.sample_field(pos.x, pos.y, Field::U),
+                fluid.sample_field(pos.x, pos.y, Field::V),
+            ),
+            pressure: fluid.sample_pressure(pos.x, pos.y),
+            smoke: 1.0 - fluid.sample_field(pos.x, pos.y, Field::S),
+            solid: fluid.s[i * fluid.num_y + j] == 0.0,
+        }
+    }
+
+    // Quantity sampled at the points of a lattice with the given spacing in simulation units,
+    // skipping points in solid cells
+    pub fn probe_grid(&self, spacing: f32, quantity: ProbeQuantity) -> Vec<(Vec2, f32)> {
+        let fluid = &self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+        let spacing = spacing.max(h);
+        let size = Vec2::new((fluid.num_x - 2) as f32, (fluid.num_y - 2) as f32) * h;
+        let columns = f32::floor(size.x / spacing) as usize;
+        let rows = f32::floor(size.y / spacing) as usize;
+        // Centers the lattice in the domain
+        let origin = Vec2::splat(h) + 0.5 * (size - Vec2::new(columns as f32, rows as f32) * spacing) + 0.5 * spacing;
+
+        let mut samples = Vec::with_capacity(columns * rows);
+        for column in 0..columns {
+            for row in 0..rows {
+                let point = origin + Vec2::new(column as f32, row as f32) * spacing;
+                let i = (point.x / h) as usize;
+                let j = (point.y / h) as usize;
+                if fluid.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let value = match quantity {
+                    ProbeQuantity::Speed => Vec2::new(
+                        fluid.sample_field(point.x, point.y, Field::U),
+                        fluid.sample_field(point.x, point.y, Field::V),
+                    ).length(),
+                    ProbeQuantity::Pressure => fluid.sample_pressure(point.x, point.y),
+                    ProbeQuantity::Vorticity => fluid.sample_vorticity(point.x, point.y),
+                };
+                samples.push((point, value));
+            }
+        }
+        samples
+    }
+
+    // Center of the grid in simulation coordinates
+    pub fn domain_center(&self) -> Vec2 {
+        0.5 * self.fluid.h * Vec2::new(self.fluid.num_x as f32, self.fluid.num_y as f32)
+    }
+
+    // Restricts the fluid to a circle, None restores the scene's rectangular domain
+    pub fn set_circular_domain(&mut self, domain: Option<CircularDomain>) {
+        self.circular_domain = domain;
+
+        let n = self.fluid.num_y;
+        for i in 0..self.fluid.num_x {
+            for j in 0..n {
+                let outside = self.outside_domain(i, j);
+                let fluid = &mut self.fluid;
+                let cell = i * n + j;
+                fluid.s[cell] = self.base_s[cell];
+                if outside {
+                    fluid.s[cell] = 0.0;
+                    fluid.m[cell] = 1.0;
+                    fluid.u[cell] = 0.0;
+                    fluid.v[cell] = 0.0;
+                    fluid.p[cell] = 0.0;
+                }
+            }
+        }
+
+        self.rasterize_obstacles();
+        if self.refinement.is_some() {
+            self.refinement = Some(self.build_refinement());
+        }
+    }
+
+    fn outside_domain(&self, i: usize, j: usize) -> bool {
+        let h = self.fluid.h;
+        let center = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+        self.circular_domain.is_some_and(|domain| !domain.contains(center))
+    }
+
+    // Wake probe position, downstream of the obstacle and kept inside the domain
+    pub fn wake_probe(&self) -> Vec2 {
+        let fluid = &self.fluid;
+        let max_x = (fluid.num_x as f32 - 1.5) * fluid.h;
+        let obstacle = self.obstacles[0];
+        Vec2::new(f32::min(obstacle.pos.x + WAKE_PROBE_OFFSET * obstacle.radius, max_x), obstacle.pos.y)
+    }
+
+    fn record_wake(&mut self) {
+        if !self.has_inflow() || self.obstacles[0].radius <= 0.0 {
+            return;
+        }
+        let probe = self.wake_probe();
+        let v = self.fluid.sample_field(probe.x, probe.y, Field::V);
+        self.wake_history.push_back((self.sim_time, v));
+        if self.wake_history.len() > WAKE_HISTORY_LENGTH {
+            self.wake_history.pop_front();
+        }
+    }
+
+    // Advances a single frame on the next step while paused
+    pub fn request_step_forward(&mut self) {
+        self.step_forward = true;
+    }
+
+    fn record_history(&mut self) {
+        if self.history_depth == 0 {
+            self.history.clear();
+            return;
+        }
+        while self.history.len() >= self.history_depth {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry {
+            fluid: self.fluid.clone(),
+            frame_nr: self.frame_nr,
+            sim_time: self.sim_time,
+            obstacles: self.obstacles.clone(),
+        });
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    // Restores the state before the last step. This replays recorded frames rather than running
+    // the simulation in reverse, so only the last history_depth frames can be revisited
+    pub fn step_back(&mut self) -> bool {
+        let Some(entry) = self.history.pop_back() else {
+            return false;
+        };
+        // The solid mask in the recorded fluid already holds the obstacles at these positions
+        self.fluid = entry.fluid;
+        self.frame_nr = entry.frame_nr;
+        self.sim_time = entry.sim_time;
+        self.obstacles = entry.obstacles;
+        if self.refinement.is_some() {
+            self.refinement = Some(self.build_refinement());
+        }
+        self.wake_history.clear();
+        true
+    }
+
+    // Adds uniform noise in [-amplitude, amplitude] to every fluid face. The caller supplies the
+    // random numbers in [0, 1) so runs can be reproduced from a seed.
+    pub fn add_velocity_noise(&mut self, amplitude: f32, mut random: impl FnMut() -> f32) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for i in 1..fluid.num_x - 1 {
+            for j in 1..fluid.num_y - 1 {
+                if fluid.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                if fluid.s[(i - 1) * n + j] != 0.0 {
+                    fluid.u[i * n + j] += amplitude * (2.0 * random() - 1.0);
+                }
+                if fluid.s[i * n + j - 1] != 0.0 {
+                    fluid.v[i * n + j] += amplitude * (2.0 * random() - 1.0);
+                }
+            }
+        }
+    }
+
+    // One time swirl around the obstacle, as if it briefly spun counterclockwise. The kick breaks
+    // the symmetry of the wake so shedding starts within a few cycles instead of waiting on
+    // numerical noise, and being deterministic it gives the same onset every time.
+    pub fn trigger_shedding(&mut self) {
+        let Obstacle { pos: center, radius: r, .. } = self.obstacles[0];
+        if r <= 0.0 {
+            return;
+        }
+        let speed = SHEDDING_KICK * VELOCITY_IN;
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+
+        for i in 1..fluid.num_x - 1 {
+            for j in 1..fluid.num_y - 1 {
+                if fluid.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                // u and v faces on the cell's left and bottom edges
+                let u_offset = Vec2::new(i as f32 * h, (j as f32 + 0.5) * h) - center;
+                let v_offset = Vec2::new((i as f32 + 0.5) * h, j as f32 * h) - center;
+                // Strongest at the surface, fading out over one radius
+                let falloff = |d: f32| if d > r && d < 2.0 * r { speed * (2.0 - d / r) } else { 0.0 };
+
+                let du = u_offset.length();
+                if du > 0.0 {
+                    fluid.u[i * n + j] -= falloff(du) * u_offset.y / du;
+                }
+                let dv = v_offset.length();
+                if dv > 0.0 {
+                    fluid.v[i * n + j] += falloff(dv) * v_offset.x / dv;
+                }
+            }
+        }
+    }
+
+    // Dominant vortex shedding frequency in Hz, from upward zero crossings of the wake probe's
+    // transverse velocity around its mean. None until two full periods have been recorded.
+    pub fn shedding_frequency(&self) -> Option<f32> {
+        let history = &self.wake_history;
+        if history.len() < 2 {
+            return None;
+        }
+        let mean = history.iter().map(|&(_, v)| v).sum::<f32>() / history.len() as f32;
+
+        let mut crossings = Vec::new();
+        for ((t0, v0), (t1, v1)) in history.iter().zip(history.iter().skip(1)) {
+            let (a, b) = (v0 - mean, v1 - mean);
+            if a < 0.0 && b >= 0.0 {
+                // Interpolate the crossing time between the samples
+                crossings.push(t0 + (t1 - t0) * (-a / (b - a)));
+            }
+        }
+
+        if crossings.len() < 3 {
+            return None;
+        }
+        let periods = (crossings.len() - 1) as f32;
+        let duration = crossings[crossings.len() - 1] - crossings[0];
+        if duration <= 0.0 {
+            return None;
+        }
+        Some(periods / duration)
+    }
+
+    // Mean pressure over the first fluid column minus the mean over the last. Being a difference
+    // it doesn't depend on the constant the pressure is defined up to.
+    pub fn pressure_drop(&self) -> f32 {
+        let fluid = &self.fluid;
+        let n = fluid.num_y;
+        let column_mean = |i: usize| {
+            let (sum, count) = (0..n)
+                .filter(|&j| fluid.s[i * n + j] != 0.0)
+                .fold((0.0, 0), |(sum, count), j| (sum + fluid.p[i * n + j], count + 1));
+            if count > 0 { sum / count as f32 } else { 0.0 }
+        };
+        column_mean(1) - column_mean(fluid.num_x - 2)
+    }
+
+    // Pressure force on the obstacle per unit depth, summed over the faces it shares with fluid
+    pub fn obstacle_force(&self) -> Vec2 {
+        let fluid = &self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+        let Obstacle { pos: obstacle_pos, radius: r, .. } = self.obstacles[0];
+        let mut force = Vec2::ZERO;
+
+        for i in 1..fluid.num_x - 1 {
+            for j in 1..fluid.num_y - 1 {
+                let center = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                if center.distance_squared(obstacle_pos) >= r * r {
+                    continue;
+                }
+                // Fluid pressure pushes against the outward normal of every wetted face
+                for (neighbour, normal) in [
+                    ((i - 1) * n + j, Vec2::NEG_X),
+                    ((i + 1) * n + j, Vec2::X),
+                    (i * n + j - 1, Vec2::NEG_Y),
+                    (i * n + j + 1, Vec2::Y),
+                ] {
+                    if fluid.s[neighbour] != 0.0 {
+                        force -= fluid.p[neighbour] * h * normal;
+                    }
+                }
+            }
+        }
+
+        force
+    }
+
+    // Drag (x) and lift (y) coefficients of the obstacle, normalized by the dynamic pressure of
+    // the inflow and the obstacle diameter. None without inflow or obstacle.
+    pub fn force_coefficients(&self) -> Option<Vec2> {
+        let diameter = 2.0 * self.obstacles[0].radius;
+        if !self.has_inflow() || diameter <= 0.0 {
+            return None;
+        }
+        let velocity = self.mean_inflow_velocity();
+        if velocity <= 0.0 {
+            return None;
+        }
+        let dynamic_pressure = 0.5 * self.fluid.density * velocity * velocity;
+        Some(self.obstacle_force() / (dynamic_pressure * diameter))
+    }
+
+    // Mean speed over the open inlet faces, follows the ramp and any driven inflow profile
+    pub fn mean_inflow_velocity(&self) -> f32 {
+        let n = self.fluid.num_y;
+        let (total, rows) = (0..n)
+            .filter(|&j| self.fluid.s[n + j] != 0.0)
+            .fold((0.0, 0), |(total, rows), j| (total + self.fluid.u[n + j], rows + 1));
+        if rows == 0 { 0.0 } else { total / rows as f32 }
+    }
+
+    // Shedding frequency made dimensionless with the obstacle diameter and the measured inflow
+    // speed. None while there is no inflow or no frequency yet.
+    pub fn strouhal_number(&self) -> Option<f32> {
+        let velocity = self.mean_inflow_velocity();
+        if velocity <= 0.0 {
+            return None;
+        }
+        self.shedding_frequency()
+            .map(|frequency| frequency * 2.0 * self.obstacles[0].radius / velocity)
+    }
+
+    fn setup_tank(&mut self) {
+        self.scene_type = SceneType::Tank;
+
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for i in 0..fluid.num_x {
+            for j in 0..fluid.num_y {
+                let mut s = 1.0; // fluid
+                if i == 0 || i == fluid.num_x - 1 || j == 0 {
+                    s = 0.0; // solid
+                }
+                fluid.s[i * n + j] = s;
+            }
+        }
+
+        self.gravity = -9.81;
+        self.tank_source_rate = 1.0;
+    }
+
+    fn setup_tunnel(&mut self, scene_type: SceneType) {
+        self.scene_type = scene_type;
+
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for i in 0..fluid.num_x {
+            for j in 0..fluid.num_y {
+                let mut s = 1.0; // fluid
+                if i == 0 || j == 0 || j == fluid.num_y - 1 {
+                    s = 0.0; //solid
+                }
+                fluid.s[i * n + j] = s;
+                if i == 1 {
+                    fluid.u[i * n + j] = VELOCITY_IN;
+                }
+            }
+        }
+
+        self.inlet_center = 0.5 * fluid.num_y as f32;
+        self.inject_inlet_smoke();
+
+        self.gravity = 0.0;
+
+        if scene_type == SceneType::HiresTunnel {
+            self.dt = 1.0 / 120.0;
+            self.num_iters = 100;
+        }
+    }
+
+    fn setup_paint(&mut self) {
+        self.scene_type = SceneType::Paint;
+
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for i in 0..fluid.num_x {
+            for j in 0..fluid.num_y {
+                let mut s = 1.0; // fluid
+                if i == 0 || i == fluid.num_x - 1 || j == 0 || j == fluid.num_y - 1 {
+                    s = 0.0; // solid
+                }
+                fluid.s[i * n + j] = s;
+            }
+        }
+
+        self.gravity = 0.0;
+        self.over_relaxation = 1.0;
+        self.obstacles[0].radius = 0.05;
+
+        self.frame_nr = 0;
+    }
+
+    fn setup_backward_step(&mut self) {
+        self.scene_type = SceneType::BackwardStep;
+
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+
+        // Solid block filling the lower half of the inlet region
+        let step_i = fluid.num_x / 6;
+        let step_j = fluid.num_y / 2;
+
+        for i in 0..fluid.num_x {
+            for j in 0..fluid.num_y {
+                let mut s = 1.0; // fluid
+                if i == 0 || j == 0 || j == fluid.num_y - 1 || (i <= step_i && j < step_j) {
+                    s = 0.0; // solid
+                }
+                fluid.s[i * n + j] = s;
+                if i == 1 && s != 0.0 {
+                    fluid.u[i * n + j] = VELOCITY_IN;
+                }
+            }
+        }
+
+        // Smoke enters through the middle of the inlet channel
+        self.inlet_center = 0.5 * (step_j + fluid.num_y) as f32;
+        self.inject_inlet_smoke();
+
+        self.gravity = 0.0;
+        // The step is the only obstacle in this scene
+        self.obstacles[0].radius = 0.0;
+    }
+
+    // Narrow jet blowing tangentially over a curved wall, which it attaches to and follows
+    fn setup_coanda(&mut self) {
+        self.scene_type = SceneType::Coanda;
+
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+
+        // Jet opening in the inlet wall
+        let jet_y = 0.75;
+        let jet_half_height = 0.04;
+        // The top of the curved wall lines up with the lower edge of the jet
+        let wall_radius = 0.26;
+        let wall_center = Vec2::new(0.35, jet_y - jet_half_height - wall_radius);
+
+        for i in 0..fluid.num_x {
+            for j in 0..fluid.num_y {
+                let center = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                let in_jet = (center.y - jet_y).abs() < jet_half_height;
+
+                let mut s = 1.0; // fluid
+                if i == 0 || j == 0 || j == fluid.num_y - 1 || (i == 1 && !in_jet) {
+                    s = 0.0; // solid
+                }
+                if (center - wall_center).length() < wall_radius {
+                    s = 0.0;
+                }
+                fluid.s[i * n + j] = s;
+                if i == 1 && s != 0.0 {
+                    fluid.u[i * n + j] = VELOCITY_IN;
+                }
+            }
+        }
+
+        self.inlet_center = jet_y / h;
+        self.inject_inlet_smoke();
+
+        self.gravity = 0.0;
+        // The curved wall is part of the scene, there is no movable obstacle
+        self.obstacles[0].radius = 0.0;
+    }
+
+    pub fn has_inflow(&self) -> bool {
+        matches!(
+            self.scene_type,
+            SceneType::WindTunnel | SceneType::HiresTunnel | SceneType::BackwardStep | SceneType::Coanda
+        )
+    }
+
+    // Marks the inlet source cells with smoke (m = 0), the solid inlet column is otherwise kept clean
+    fn inject_inlet_smoke(&mut self) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+        let center = self.inlet_center;
+
+        // Streaks contrast with whatever the domain was filled with
+        let background = self.initial_smoke;
+        let streak = if background > 0.5 { 0.0 } else { 1.0 };
+        fluid.m[..n].fill(background);
+
+        match self.inlet_source {
+            InletSource::Band => {
+                let band_height = self.inlet_band_fraction * fluid.num_y as f32;
+                let min_j = f32::floor(center - 0.5 * band_height) as usize;
+                let max_j = usize::min(f32::floor(center + 0.5 * band_height) as usize, n);
+
+                for j in min_j..max_j {
+                    fluid.m[j] = streak;
+                }
+            }
+            InletSource::Point => {
+                let j = f32::floor(center) as usize;
+                if fluid.s[n + j] != 0.0 {
+                    fluid.m[n + j] = streak;
+                }
+            }
+            InletSource::Line => {
+                for j in (1..fluid.num_y - 1).step_by(LINE_SOURCE_SPACING) {
+                    if fluid.s[n + j] != 0.0 {
+                        fluid.m[n + j] = streak;
+                    }
+                }
+            }
+            InletSource::Ring => {
+                let ring_center = Vec2::new(RING_SOURCE_OFFSET, (center + 0.5) * h);
+                for i in 1..fluid.num_x - 1 {
+                    for j in 1..fluid.num_y - 1 {
+                        let cell = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                        let d = cell.distance(ring_center) - RING_SOURCE_RADIUS;
+                        if d.abs() < 0.5 * h && fluid.s[i * n + j] != 0.0 {
+                            fluid.m[i * n + j] = streak;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Releases smoke at the middle of the tank floor and pushes it upwards, standing in for a
+    // heated source
+    fn apply_tank_source(&mut self, dt: f32) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+        let center = 0.5 * fluid.num_x as f32 * h;
+        let keep = f32::exp(-self.tank_source_rate * dt);
+        let speed = TANK_SOURCE_SPEED * self.tank_source_rate.min(1.0);
+
+        for i in 1..fluid.num_x - 1 {
+            let x = (i as f32 + 0.5) * h;
+            if (x - center).abs() > TANK_SOURCE_HALF_WIDTH {
+                continue;
+            }
+            for j in 1..3 {
+                if fluid.s[i * n + j] != 0.0 {
+                    fluid.m[i * n + j] *= keep;
+                }
+            }
+            if fluid.s[i * n + 1] != 0.0 && fluid.s[i * n + 2] != 0.0 {
+                fluid.v[i * n + 2] = fluid.v[i * n + 2].max(speed);
+            }
+        }
+    }
+
+    // Prescribes the outflow on the faces above the bottom wall. Like the tunnel inlet these faces
+    // border a solid, so the projection keeps them fixed and balances them with inflow at the top
+    // instead of letting gravity accelerate the whole column through an open boundary
+    fn apply_tank_drain(&mut self) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for i in 1..fluid.num_x - 1 {
+            if fluid.s[i * n + 1] != 0.0 {
+                fluid.v[i * n + 1] = -self.tank_drain_speed;
+            }
+        }
+    }
+
+    // Inlet speed at the current sim time, eased in with a smoothstep over the ramp duration
+    pub fn inflow_velocity(&self) -> f32 {
+        if self.inflow_ramp_seconds <= 0.0 {
+            return VELOCITY_IN;
+        }
+        let t = (self.sim_time / self.inflow_ramp_seconds).clamp(0.0, 1.0);
+        VELOCITY_IN * t * t * (3.0 - 2.0 * t)
+    }
+
+    // Sets the faces between the wall column and the first fluid column to the inlet speed
+    fn apply_inflow(&mut self) {
+        let velocity = self.inflow_velocity();
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        for j in 0..n {
+            if self.base_s[n + j] != 0.0 {
+                fluid.u[n + j] = match &self.inflow_profile {
+                    // The ramp eases the profile in the same way as the uniform inflow
+                    Some(profile) => profile[j] * velocity / VELOCITY_IN,
+                    None => velocity,
+                };
+            }
+        }
+    }
+
+    // Drives the inlet with one speed per row, e.g. computed by another simulation. Can be called
+    // every frame, the profile is applied at the start of each step until cleared.
+    pub fn set_inflow_profile(&mut self, profile: &[f32]) {
+        if profile.len() != self.fluid.num_y {
+            warn!("Inflow profile has {} rows but the grid has {}, keeping the current inflow", profile.len(), self.fluid.num_y);
+            return;
+        }
+        match self.inflow_profile.as_mut() {
+            Some(current) => current.copy_from_slice(profile),
+            None => self.inflow_profile = Some(profile.to_vec()),
+        }
+    }
+
+    pub fn clear_inflow_profile(&mut self) {
+        self.inflow_profile = None;
+    }
+
+    // Refills the whole domain, meant to be called right after creating the scene
+    pub fn set_initial_smoke(&mut self, value: f32) {
+        self.initial_smoke = value.clamp(0.0, 1.0);
+        self.fluid.m.fill(self.initial_smoke);
+        if self.has_inflow() {
+            self.inject_inlet_smoke();
+        }
+    }
+
+    pub fn has_inflow_profile(&self) -> bool {
+        self.inflow_profile.is_some()
+    }
+
+    // Damps velocities within clamp_border cells of the solid domain walls, strongest at the wall
+    // and fading out linearly. Lighter than a sponge layer, it only keeps edge artifacts in check
+    fn relax_border(&mut self, dt: f32) {
+        let has_inflow = self.has_inflow();
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let (num_x, num_y) = (fluid.num_x, fluid.num_y);
+        let width = self.clamp_border as f32;
+
+        // An edge counts as a wall when the scene geometry is solid at its middle
+        let left = self.base_s[n / 2] == 0.0 && !has_inflow;
+        let right = self.base_s[(num_x - 1) * n + n / 2] == 0.0;
+        let bottom = self.base_s[(num_x / 2) * n] == 0.0;
+        let top = self.base_s[(num_x / 2) * n + num_y - 1] == 0.0;
+
+        for i in 1..num_x - 1 {
+            for j in 1..num_y - 1 {
+                let distances = [
+                    left.then_some(i - 1),
+                    right.then_some(num_x - 2 - i),
+                    bottom.then_some(j - 1),
+                    top.then_some(num_y - 2 - j),
+                ];
+                let Some(distance) = distances.into_iter().flatten().min() else {
+                    continue;
+                };
+                let weight = 1.0 - distance as f32 / width;
+                if weight <= 0.0 || fluid.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let keep = f32::exp(-BORDER_RELAXATION_RATE * weight * dt);
+                // Faces against a solid are set by the boundary conditions
+                if fluid.s[(i - 1) * n + j] != 0.0 {
+                    fluid.u[i * n + j] *= keep;
+                }
+                if fluid.s[i * n + j - 1] != 0.0 {
+                    fluid.v[i * n + j] *= keep;
+                }
+            }
+        }
+    }
+
+    // The inflow velocity lives on the faces of the first fluid column, an obstacle covering that
+    // column would fight the inflow. Obstacles are pushed downstream until they clear it instead.
+    pub fn clear_of_inlet(&self, pos: Vec2, radius: f32) -> Vec2 {
+        if !self.has_inflow() {
+            return pos;
+        }
+        let min_x = 2.0 * self.fluid.h + radius;
+        Vec2::new(f32::max(pos.x, min_x), pos.y)
+    }
+
+    pub fn obstacle_in_bounds(&self, pos: Vec2) -> bool {
+        // Bounds follow the grid, not the image the scene is drawn into
+        let domain_width = (self.fluid.num_x - 2) as f32 * self.fluid.h;
+        let domain_height = (self.fluid.num_y - 2) as f32 * self.fluid.h;
+        pos.x >= 0.2 && pos.x <= domain_width - 0.1 && pos.y >= 0.1 && pos.y <= domain_height - 0.1
+    }
+
+    // Moves the draggable obstacle
+    pub fn set_obstacle(&mut self, pos: Vec2, reset: bool) {
+        self.move_obstacle(0, pos, reset);
+    }
+
+    // Exponential moving average of the drag velocity to damp jittery input, capped at
+    // max_obstacle_speed
+    fn drag_velocity(&mut self, from: Vec2, to: Vec2, previous: Vec2) -> Vec2 {
+        let mut raw = (to - from) / self.dt;
+        if raw.length() > self.max_obstacle_speed {
+            raw = raw.clamp_length_max(self.max_obstacle_speed);
+            self.obstacle_speed_clamped = Some(Instant::now());
+        }
+        previous.lerp(raw, 1.0 - self.obstacle_smoothing)
+    }
+
+    // Adds a fixed obstacle and returns its id, None when pos is outside of the domain. The radius
+    // is limited like the draggable obstacle's
+    pub fn add_obstacle(&mut self, pos: Vec2, radius: f32) -> Option<usize> {
+        if !self.obstacle_in_bounds(pos) {
+            return None;
+        }
+        let max_radius = MAX_OBSTACLE_RADIUS * self.fluid.num_y as f32 * self.fluid.h;
+        let radius = radius.clamp(self.fluid.h, max_radius);
+        let pos = self.clear_of_inlet(pos, radius);
+        self.obstacles.push(Obstacle { pos, radius, vel: Vec2::ZERO });
+        self.update_obstacles();
+        Some(self.obstacles.len() - 1)
+    }
+
+    // All obstacles by id, the draggable one first
+    pub fn obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    // Removes every obstacle but the draggable one
+    pub fn clear_fixed_obstacles(&mut self) {
+        self.obstacles.truncate(1);
+        self.update_obstacles();
+    }
+
+    // Id of the obstacle covering pos, the last added first since it is on top
+    pub fn obstacle_at(&self, pos: Vec2) -> Option<usize> {
+        self.obstacles.iter().rposition(|obstacle| obstacle.pos.distance(pos) < obstacle.radius)
+    }
+
+    // Moves the obstacle with the given id, leaving the others in place. Without reset it moves
+    // the fluid along at the drag velocity. Unknown ids and positions outside of the domain are
+    // ignored
+    pub fn move_obstacle(&mut self, id: usize, pos: Vec2, reset: bool) {
+        let Some(&obstacle) = self.obstacles.get(id) else {
+            return;
+        };
+        if !self.obstacle_in_bounds(pos) {
+            return;
+        }
+        let pos = self.clear_of_inlet(pos, obstacle.radius);
+        let vel = if reset { Vec2::ZERO } else { self.drag_velocity(obstacle.pos, pos, obstacle.vel) };
+
+        // The old wake no longer describes the flow around the moved obstacle
+        if pos != obstacle.pos {
+            self.wake_history.clear();
+        }
+        self.obstacles[id] = Obstacle { pos, vel, ..obstacle };
+        self.update_obstacles();
+    }
+
+    // Whether a drag was recently faster than max_obstacle_speed
+    pub fn obstacle_too_fast(&self) -> bool {
+        match self.obstacle_speed_clamped {
+            Some(clamped) => clamped.elapsed() < OBSTACLE_SPEED_WARNING,
+            None => false,
+        }
+    }
+
+    // Every obstacle by id, a removed draggable obstacle stays as an entry with radius 0 so the
+    // fixed ones keep their ids
+    fn obstacle_layout(&self) -> Vec<ObstacleConfig> {
+        self.obstacles.iter().enumerate().map(|(id, obstacle)| ObstacleConfig {
+            position: obstacle.pos.to_array(),
+            radius: obstacle.radius,
+            velocity: obstacle.vel.to_array(),
+            rotation_rate: if id == 0 { self.obstacle_rotation_rate } else { 0.0 },
+        }).collect()
+    }
+
+    pub fn save_obstacles(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let layout = ObstacleLayout { obstacles: self.obstacle_layout() };
+        let json = serde_json::to_string_pretty(&layout)?;
+        std::fs::write(path, json)
+    }
+
+    // Replaces the current obstacles with the ones in the layout file
+    pub fn load_obstacles(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let json = std::fs::read_to_string(path)?;
+        let layout: ObstacleLayout = serde_json::from_str(&json)?;
+        self.apply_obstacle_layout(&layout.obstacles)
+    }
+
+    // The first entry becomes the draggable obstacle and the rest fixed obstacles, an empty layout
+    // removes them all. Only the draggable obstacle spins
+    fn apply_obstacle_layout(&mut self, obstacles: &[ObstacleConfig]) -> io::Result<()> {
+        // A removed draggable obstacle has no position to check
+        for obstacle in obstacles.iter().filter(|obstacle| obstacle.radius > 0.0) {
+            let pos = Vec2::from_array(obstacle.position);
+            if !self.obstacle_in_bounds(pos) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("obstacle position {} is outside of the domain", pos)));
+            }
+        }
+        self.obstacles.truncate(1);
+
+        match obstacles.first() {
+            Some(obstacle) => {
+                // Loaded obstacles get the same radius limit and inlet clearance as placed ones
+                self.set_obstacle_radius(obstacle.radius);
+                self.obstacle_rotation_rate = obstacle.rotation_rate;
+                self.set_obstacle(Vec2::from_array(obstacle.position), true);
+                self.obstacles[0].vel = Vec2::from_array(obstacle.velocity);
+            }
+            None => {
+                self.obstacles[0] = Obstacle { radius: 0.0, vel: Vec2::ZERO, ..self.obstacles[0] };
+                self.obstacle_rotation_rate = 0.0;
+            }
+        }
+        for obstacle in obstacles.iter().skip(1) {
+            if let Some(id) = self.add_obstacle(Vec2::from_array(obstacle.position), obstacle.radius) {
+                self.obstacles[id].vel = Vec2::from_array(obstacle.velocity);
+            }
+        }
+        self.update_obstacles();
+
+        Ok(())
+    }
+
+    // Resizes the draggable obstacle in place. Radii under a cell remove it, the upper bound keeps
+    // it from closing off the domain.
+    pub fn set_obstacle_radius(&mut self, radius: f32) {
+        let max_radius = MAX_OBSTACLE_RADIUS * self.fluid.num_y as f32 * self.fluid.h;
+        self.obstacles[0].radius = if radius < self.fluid.h { 0.0 } else { radius.min(max_radius) };
+        self.set_obstacle(self.obstacles[0].pos, true);
+    }
+
+    // Rasterizes the obstacles after one changed, the refinement patch is rebuilt since it holds
+    // them as well
+    fn update_obstacles(&mut self) {
+        self.rasterize_obstacles();
+        if self.refinement.is_some() {
+            self.refinement = Some(self.build_refinement());
+        }
+    }
+
+    // Marks the obstacles' cells solid and gives their faces the obstacles' surface velocity: the
+    // drag velocity, plus for the draggable obstacle the rotation about its center. Where obstacles
+    // overlap the one added last sets the velocity
+    fn rasterize_obstacles(&mut self) {
+        let fluid = &mut self.fluid;
+        let obstacles = &self.obstacles;
+        let domain = self.circular_domain;
+        let spin = self.obstacle_rotation_rate;
+        let n = fluid.num_y;
+        let h = fluid.h;
+        let k = self.obstacle_subsamples.max(1);
+
+        let omega = |id: usize| if id == 0 { spin } else { 0.0 };
+        // Velocity of the surface point at offset from the center of the obstacle with this id
+        let surface_velocity = |id: usize, offset: Vec2| {
+            let (vel, omega) = (obstacles[id].vel, omega(id));
+            Vec2::new(vel.x - omega * offset.y, vel.y + omega * offset.x)
+        };
+
+        // Share of the cell's k x k sub-points inside the circle around pos with radius r
+        let coverage = |center: Vec2, pos: Vec2, r: f32| {
+            let d = (center - pos).length();
+            if d >= r + h {
+                return 0.0;
+            }
+            if d + h <= r {
+                return 1.0;
+            }
+            let mut inside = 0;
+            for si in 0..k {
+                for sj in 0..k {
+                    let offset = Vec2::new(si as f32 + 0.5, sj as f32 + 0.5) / k as f32 - 0.5;
+                    if (center + offset * h - pos).length_squared() < r * r {
+                        inside += 1;
+                    }
+                }
+            }
+            inside as f32 / (k * k) as f32
+        };
+
+        for i in 1..fluid.num_x - 2 {
+            for j in 1..fluid.num_y - 2 {
+                let center = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                let outside = domain.is_some_and(|domain| !domain.contains(center));
+                fluid.s[i * n + j] = if outside { 0.0 } else { self.base_s[i * n + j] };
 
-                if dx * dx + dy * dy < r * r {
+                let mut surface = None;
+                for (id, obstacle) in obstacles.iter().enumerate() {
+                    if coverage(center, obstacle.pos, obstacle.radius) >= 0.5 {
+                        surface = Some(surface_velocity(id, center - obstacle.pos));
+                    }
+                }
+
+                if let Some(surface) = surface {
                     fluid.s[i * n + j] = 0.0;
                     fluid.m[i * n + j] = if self.scene_type == SceneType::Paint { 0.5 + 0.5 * f32::sin(0.1 * 2.0) } else { 1.0 };
-                    fluid.u[i * n + j] = v.x;
-                    fluid.u[(i + 1) * n + j] = v.x;
-                    fluid.v[i * n + j] = v.y;
-                    fluid.v[i * n + (j + 1)] = v.y;
+                    // u faces sit at the cell's left and right edges, v faces at its bottom and top
+                    fluid.u[i * n + j] = surface.x;
+                    fluid.u[(i + 1) * n + j] = surface.x;
+                    fluid.v[i * n + j] = surface.y;
+                    fluid.v[i * n + (j + 1)] = surface.y;
+                }
+            }
+        }
+
+        // Blend the fluid faces in a band around each moving surface towards the surface velocity
+        // so the solver doesn't have to absorb a hard jump at the edge
+        let band = self.obstacle_feather * h;
+        if band <= 0.0 {
+            return;
+        }
+        for (id, obstacle) in obstacles.iter().enumerate() {
+            let r = obstacle.radius;
+            if r <= 0.0 || (obstacle.vel == Vec2::ZERO && omega(id) == 0.0) {
+                continue;
+            }
+            let weight = |offset: Vec2| {
+                let d = offset.length();
+                if d >= r && d < r + band { 1.0 - (d - r) / band } else { 0.0 }
+            };
+            for i in 2..fluid.num_x - 2 {
+                for j in 2..fluid.num_y - 2 {
+                    if fluid.s[i * n + j] == 0.0 {
+                        continue;
+                    }
+                    let u_offset = Vec2::new(i as f32 * h, (j as f32 + 0.5) * h) - obstacle.pos;
+                    let w = weight(u_offset);
+                    if w > 0.0 && fluid.s[(i - 1) * n + j] != 0.0 {
+                        let u = &mut fluid.u[i * n + j];
+                        *u += w * (surface_velocity(id, u_offset).x - *u);
+                    }
+                    let v_offset = Vec2::new((i as f32 + 0.5) * h, j as f32 * h) - obstacle.pos;
+                    let w = weight(v_offset);
+                    if w > 0.0 && fluid.s[i * n + j - 1] != 0.0 {
+                        let face = &mut fluid.v[i * n + j];
+                        *face += w * (surface_velocity(id, v_offset).y - *face);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_refinement(&mut self, enabled: bool) {
+        self.refinement = if enabled { Some(self.build_refinement()) } else { None };
+    }
+
+    // Creates a patch centered on the draggable obstacle, initialized from the coarse fields
+    fn build_refinement(&self) -> RefinementPatch {
+        let coarse = &self.fluid;
+        let h = coarse.h;
+        let size = self.refinement_size.clamp(4, usize::min(coarse.num_x, coarse.num_y) - 2);
+        let factor = self.refinement_factor.max(1);
+
+        // Snap to coarse cells and keep the patch inside the domain walls
+        let max_i = (coarse.num_x - 1 - size) as isize;
+        let max_j = (coarse.num_y - 1 - size) as isize;
+        let center = self.obstacles[0].pos;
+        let i0 = (f32::floor(center.x / h) as isize - size as isize / 2).clamp(1, max_i) as usize;
+        let j0 = (f32::floor(center.y / h) as isize - size as isize / 2).clamp(1, max_j) as usize;
+
+        let fine_h = h / factor as f32;
+        let cells = size * factor + 2;
+        let mut patch = RefinementPatch {
+            fluid: Fluid::new(coarse.density, cells, cells, fine_h),
+            factor,
+            start: (i0, j0),
+            size,
+        };
+
+        let origin = patch.origin(h);
+        let fine = &mut patch.fluid;
+        for fi in 0..cells {
+            for fj in 0..cells {
+                let x = origin.x + fi as f32 * fine_h;
+                let y = origin.y + fj as f32 * fine_h;
+                let center = Vec2::new(x + 0.5 * fine_h, y + 0.5 * fine_h);
+
+                let ci = usize::min(f32::floor(center.x / h) as usize, coarse.num_x - 1);
+                let cj = usize::min(f32::floor(center.y / h) as usize, coarse.num_y - 1);
+                let ring = fi == 0 || fj == 0 || fi == cells - 1 || fj == cells - 1;
+                let in_obstacle = self.obstacles.iter().any(|obstacle| center.distance_squared(obstacle.pos) < obstacle.radius * obstacle.radius);
+                let solid = ring || in_obstacle || self.base_s[ci * coarse.num_y + cj] == 0.0 || self.outside_domain(ci, cj);
+
+                let cell = fi * cells + fj;
+                fine.s[cell] = if solid { 0.0 } else { 1.0 };
+                fine.m[cell] = coarse.sample_field(center.x, center.y, Field::S);
+                fine.u[cell] = coarse.sample_field(x, center.y, Field::U);
+                fine.v[cell] = coarse.sample_field(center.x, y, Field::V);
+            }
+        }
+
+        patch
+    }
+
+    // Sets the porous resistance of every cell within radius of pos, the inlet column is left free
+    pub fn paint_resistance(&mut self, pos: Vec2, radius: f32, resistance: f32) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+
+        for i in 2..fluid.num_x - 1 {
+            for j in 1..fluid.num_y - 1 {
+                let dx = (i as f32 + 0.5) * h - pos.x;
+                let dy = (j as f32 + 0.5) * h - pos.y;
+                if dx * dx + dy * dy < radius * radius {
+                    fluid.resistance[i * n + j] = resistance;
+                }
+            }
+        }
+    }
+
+    // Pins the cells within radius to a velocity, None releases them
+    pub fn paint_forced(&mut self, pos: Vec2, radius: f32, velocity: Option<Vec2>) {
+        let fluid = &mut self.fluid;
+        let n = fluid.num_y;
+        let h = fluid.h;
+
+        for i in 1..fluid.num_x - 1 {
+            for j in 1..fluid.num_y - 1 {
+                let dx = (i as f32 + 0.5) * h - pos.x;
+                let dy = (j as f32 + 0.5) * h - pos.y;
+                if dx * dx + dy * dy < radius * radius {
+                    fluid.forced[i * n + j] = velocity;
                 }
             }
         }
@@ -226,74 +2410,367 @@ impl FluidScene {
         let fluid = &self.fluid;
 
         let h = fluid.h;
-        let cx = f32::floor(self.scale * h) as usize + 1;
-        let cy = f32::floor(self.scale * h) as usize + 1;
+        let stride = self.effective_render_stride();
+        let cx = f32::floor(self.scale * h * stride as f32) as usize + 1;
+        let cy = f32::floor(self.scale * h * stride as f32) as usize + 1;
+        let n = fluid.num_y;
+        let channels = self.render_format.channels();
+
+        let mut color = [255; 4];
+
+        let mut p_min = fluid.p[0];
+        let mut p_max = fluid.p[0];
+        if self.show_pressure || self.render_format == RenderFormat::Pressure {
+            for i in 0..fluid.num_cells {
+                p_min = f32::min(p_min, fluid.p[i]);
+                p_max = f32::max(p_max, fluid.p[i]);
+            }
+        }
+
+        let overlays = Overlays {
+            contours: self.stream_contours(),
+            shear: self.shear_overlay(),
+        };
+
+        if self.smooth_render || self.render_region.is_some() {
+            self.draw_per_pixel(render_buffer, p_min, p_max, self.smooth_render, &overlays);
+            return;
+        }
+
+        // With a stride above 1 every block of stride x stride cells takes the color of its first
+        for i in (0..fluid.num_x).step_by(stride) {
+            for j in (0..fluid.num_y).step_by(stride) {
+                let cell = i * n + j;
+                if self.outside_domain(i, j) && !self.show_mask {
+                    set_color(&mut color, &FluidTheme::rgb255(self.theme.background));
+                } else {
+                    self.shade(&mut color, fluid.s[cell] == 0.0, fluid.p[cell], fluid.m[cell], p_min, p_max);
+                }
+                self.paint_overlays(&mut color, &overlays, i, j);
+
+                let x = f32::floor(self.c_x((i as f32 - 1.0) * h, self.scale)) as usize;
+                let y = f32::floor(self.c_y((j + stride) as f32 * h, self.height, self.scale)) as usize;
+                // Clip the block to the image so edge blocks neither wrap into the next row nor
+                // leave the last row and column unwritten
+                let width = self.width as usize;
+                let height = self.height as usize;
+                for yi in y..usize::min(y + cy, height) {
+                    for xi in x..usize::min(x + cx, width) {
+                        let p = channels * (yi * width + xi);
+                        if let Some(pixel) = render_buffer.get_mut(p..p + channels) {
+                            pixel.copy_from_slice(&color[..channels]);
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(patch) = &self.refinement {
+            self.draw_refinement(patch, render_buffer, p_min, p_max);
+        }
+    }
+
+    // Draws the fine cells of the refinement patch over the coarse grid
+    fn draw_refinement(&self, patch: &RefinementPatch, render_buffer: &mut [u8], p_min: f32, p_max: f32) {
+        let fine = &patch.fluid;
+        let h = self.fluid.h;
+        let fine_h = fine.h;
+        let origin = patch.origin(h);
+        let n = fine.num_y;
+        let channels = self.render_format.channels();
+        let width = self.width as usize;
+        let block = f32::floor(self.scale * fine_h) as usize + 1;
+
+        let mut color = [255; 4];
+
+        for fi in 1..fine.num_x - 1 {
+            for fj in 1..fine.num_y - 1 {
+                let cell = fi * n + fj;
+                self.shade(&mut color, fine.s[cell] == 0.0, fine.p[cell], fine.m[cell], p_min, p_max);
+
+                let x = f32::floor(self.c_x(origin.x + fi as f32 * fine_h - h, self.scale)) as usize;
+                let y = f32::floor(self.c_y(origin.y + (fj as f32 + 1.0) * fine_h, self.height, self.scale)) as usize;
+                for yi in y..y + block {
+                    for xi in (x..x + block).filter(|&xi| xi < width) {
+                        let index = channels * (yi * width + xi);
+                        if index + channels <= render_buffer.len() {
+                            render_buffer[index..index + channels].copy_from_slice(&color[..channels]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Cells per drawn block side, automatic mode picks roughly one cell per pixel
+    pub fn effective_render_stride(&self) -> usize {
+        match self.render_stride {
+            Some(stride) => stride.max(1),
+            None => usize::max(1, f32::floor(1.0 / (self.scale * self.fluid.h)) as usize),
+        }
+    }
+
+    // Simulation position shown at the center of a pixel, stretching the render region over the
+    // image when one is set
+    fn pixel_to_sim(&self, px: usize, py: usize) -> Vec2 {
+        match self.render_region {
+            Some(region) => Vec2::new(
+                region.min.x + (px as f32 + 0.5) / self.width * region.width(),
+                region.max.y - (py as f32 + 0.5) / self.height * region.height(),
+            ),
+            None => Vec2::new(
+                (px as f32 + 0.5) / self.scale + self.fluid.h,
+                (self.height - (py as f32 + 0.5)) / self.scale,
+            ),
+        }
+    }
+
+    // Colors every pixel from the cell under it, or from fields interpolated between cell
+    // centers when interpolate is set
+    fn draw_per_pixel(&self, render_buffer: &mut [u8], p_min: f32, p_max: f32, interpolate: bool, overlays: &Overlays) {
+        let fluid = &self.fluid;
+        let h = fluid.h;
         let n = fluid.num_y;
+        let channels = self.render_format.channels();
+        let width = self.width as usize;
+
+        let mut color = [255; 4];
+
+        for py in 0..self.height as usize {
+            for px in 0..width {
+                let pos = self.pixel_to_sim(px, py);
+                let i = usize::min(f32::floor(pos.x.max(0.0) / h) as usize, fluid.num_x - 1);
+                let j = usize::min(f32::floor(pos.y.max(0.0) / h) as usize, fluid.num_y - 1);
+                let cell = i * n + j;
+
+                let (p, m) = if interpolate {
+                    (fluid.sample_pressure(pos.x, pos.y), fluid.sample_field(pos.x, pos.y, Field::S))
+                } else {
+                    (fluid.p[cell], fluid.m[cell])
+                };
+                if self.outside_domain(i, j) && !self.show_mask {
+                    set_color(&mut color, &FluidTheme::rgb255(self.theme.background));
+                } else {
+                    self.shade(&mut color, fluid.s[cell] == 0.0, p, m, p_min, p_max);
+                }
+                self.paint_overlays(&mut color, overlays, i, j);
+
+                let index = channels * (py * width + px);
+                if index + channels <= render_buffer.len() {
+                    render_buffer[index..index + channels].copy_from_slice(&color[..channels]);
+                }
+            }
+        }
+    }
+
+    // Shear coloring, Courant warnings and stream function contours, in that order
+    fn paint_overlays(&self, color: &mut [u8; 4], overlays: &Overlays, i: usize, j: usize) {
+        let n = self.fluid.num_y;
+        if let Some((shear, max)) = overlays.shear.as_ref() {
+            if self.fluid.s[i * n + j] != 0.0 {
+                set_color(color, &self.colormap.map_range(shear[i * n + j], 0.0, *max));
+            }
+        }
+        if self.courant_warning(i, j) {
+            set_color(color, &COURANT_WARNING);
+        }
+        if let Some((psi, spacing)) = overlays.contours.as_ref() {
+            if on_stream_contour(psi, n, i, j, *spacing) {
+                set_color(color, &FluidTheme::rgb255(self.theme.gizmo));
+            }
+        }
+    }
+
+    // Strain rate magnitude of every cell and its maximum, only drawn in the color format
+    fn shear_overlay(&self) -> Option<(Vec<f32>, f32)> {
+        if !self.show_shear || self.render_format != RenderFormat::Rgba {
+            return None;
+        }
+        let shear = self.fluid.strain_rate();
+        let max = shear.iter().fold(0.0f32, |max, value| max.max(*value));
+        Some((shear, max))
+    }
+
+    // Stream function and the spacing of its drawn contours, only drawn in the color format
+    fn stream_contours(&self) -> Option<(Vec<f32>, f32)> {
+        if !self.show_stream_function || self.render_format != RenderFormat::Rgba {
+            return None;
+        }
+        let psi = self.fluid.stream_function();
+        let (min, max) = psi.iter().fold((f32::MAX, f32::MIN), |(min, max), value| (min.min(*value), max.max(*value)));
+        let spacing = (max - min) / STREAM_CONTOURS;
+        (spacing > 0.0).then_some((psi, spacing))
+    }
+
+    // Fluid cells moving more than one cell per step, only marked in the color format
+    fn courant_warning(&self, i: usize, j: usize) -> bool {
+        self.show_courant
+            && self.render_format == RenderFormat::Rgba
+            && self.fluid.s[i * self.fluid.num_y + j] != 0.0
+            && self.fluid.courant(i, j, self.dt) > 1.0
+    }
+
+    // Writes the color for a pressure and smoke value
+    fn shade(&self, color: &mut [u8; 4], solid: bool, p: f32, m: f32, p_min: f32, p_max: f32) {
+        // The dye amount is normalized to [0, 1] for coloring, clear fluid stays at 1
+        let m = 1.0 - (1.0 - m) / self.max_smoke;
+        if self.show_mask {
+            color_into_all(color, if solid { 0.0 } else { 255.0 });
+        } else if let Some(value) = self.scalar_output(p, m, p_min, p_max) {
+            color[0] = value;
+        } else if self.show_pressure {
+            let sci_color = self.colormap.map_range(p, p_min, p_max);
+            if self.show_smoke {
+                set_color(
+                    color,
+                    &[
+                        f32::max(0.0, sci_color[0] - 255.0 * m),
+                        f32::max(0.0, sci_color[1] - 255.0 * m),
+                        f32::max(0.0, sci_color[2] - 255.0 * m),
+                    ],
+                );
+            } else {
+                set_color(color, &sci_color);
+            }
+        } else if self.show_smoke {
+            if self.show_smoke_gradient {
+                set_color(color, &self.colormap.map(m));
+            } else {
+                let dye = self.dye_color.map(|c| c as f32);
+                set_color(color, &dye.map(|c| c + (255.0 - c) * m));
+            }
+        } else if solid {
+            set_color(color, &FluidTheme::rgb255(self.theme.solid));
+        } else {
+            set_color(color, &FluidTheme::rgb255(self.theme.background));
+        }
+    }
+
+    // Raw value written to single channel render formats, None when rendering in color
+    fn scalar_output(&self, p: f32, m: f32, p_min: f32, p_max: f32) -> Option<u8> {
+        let value = match self.render_format {
+            RenderFormat::Rgba => return None,
+            RenderFormat::Pressure => {
+                let d = p_max - p_min;
+                if d == 0.0 { 0.5 } else { (p - p_min) / d }
+            }
+            RenderFormat::Smoke => m,
+        };
+        Some(f32::floor(255.0 * value.clamp(0.0, 1.0)) as u8)
+    }
+
+    pub fn c_x(&self, x: f32, scale: f32) -> f32 {
+        x * scale
+    }
+
+    pub fn c_y(&self, y: f32, height: f32, scale: f32) -> f32 {
+        height - y * scale
+    }
+}
+
+// Finer sub-grid around the obstacle. Its outer ring of cells is driven by the coarse grid and
+// its interior is averaged back into the coarse grid after every step.
+pub struct RefinementPatch {
+    pub fluid: Fluid,
+    pub factor: usize,
+    // First coarse cell covered by the patch
+    pub start: (usize, usize),
+    // Coarse cells per side
+    pub size: usize,
+}
+
+impl RefinementPatch {
+    // Lower left corner of the fine grid, including its outer ring, in simulation coordinates
+    pub fn origin(&self, coarse_h: f32) -> Vec2 {
+        Vec2::new(
+            self.start.0 as f32 * coarse_h - self.fluid.h,
+            self.start.1 as f32 * coarse_h - self.fluid.h,
+        )
+    }
+
+    fn step(&mut self, coarse: &mut Fluid, dt: f32, gravity: f32, scheme: TimeIntegration, num_iters: usize, over_relaxation: f32) {
+        self.apply_boundary(coarse);
+
+        self.fluid.damping_x = coarse.damping_x;
+        self.fluid.damping_y = coarse.damping_y;
+        self.fluid.integrate(dt, gravity, scheme);
+        self.fluid.p.fill(0.0);
+        self.fluid.solve_incompressibility(dt, num_iters as i32, over_relaxation, None);
+        self.fluid.advect_vel(dt);
+        // The patch sits in open flow around the obstacle, so it keeps the default wall handling
+        self.fluid.advect_smoke(dt, WallSmokeMode::Absorb);
+
+        self.restrict(coarse);
+    }
+
+    // Samples the coarse grid into the outer ring and the faces between it and the interior
+    fn apply_boundary(&mut self, coarse: &Fluid) {
+        let origin = self.origin(coarse.h);
+        let fine = &mut self.fluid;
+        let n = fine.num_y;
+        let h = fine.h;
+        let last = fine.num_x - 1;
 
-        let mut color = [255; 4];
+        for fi in 0..fine.num_x {
+            for fj in 0..fine.num_y {
+                let x = origin.x + fi as f32 * h;
+                let y = origin.y + fj as f32 * h;
+                let ring = fi == 0 || fj == 0 || fi == last || fj == last;
 
-        let mut p_min = fluid.p[0];
-        let mut p_max = fluid.p[0];
-        if self.show_pressure {
-            for i in 0..fluid.num_cells {
-                p_min = f32::min(p_min, fluid.p[i]);
-                p_max = f32::max(p_max, fluid.p[i]);
+                if ring || fi == 1 {
+                    fine.u[fi * n + fj] = coarse.sample_field(x, y + 0.5 * h, Field::U);
+                }
+                if ring || fj == 1 {
+                    fine.v[fi * n + fj] = coarse.sample_field(x + 0.5 * h, y, Field::V);
+                }
+                if ring {
+                    fine.m[fi * n + fj] = coarse.sample_field(x + 0.5 * h, y + 0.5 * h, Field::S);
+                }
             }
         }
+    }
 
-        for i in 0..fluid.num_x {
-            for j in 0..fluid.num_y {
-                if self.show_pressure {
-                    let p = fluid.p[i * n + j];
-                    let s = fluid.m[i * n + j];
-                    let sci_color = get_sci_color(p, p_min, p_max);
-                    if self.show_smoke {
-                        set_color(
-                            &mut color,
-                            &[
-                                f32::max(0.0, sci_color[0] - 255.0 * s),
-                                f32::max(0.0, sci_color[1] - 255.0 * s),
-                                f32::max(0.0, sci_color[2] - 255.0 * s),
-                            ],
-                        );
-                    } else {
-                        set_color(&mut color, &sci_color);
-                    }
-                } else if self.show_smoke {
-                    let s = fluid.m[i * n + j];
-                    if self.show_smoke_gradient {
-                        let sci_color = get_sci_color(s, 0.0, 1.0);
-                        set_color(&mut color, &sci_color);
-                    } else {
-                        color_into_all(&mut color, 255.0 * s);
+    // Averages the fine solution back into the coarse cells it covers
+    fn restrict(&self, coarse: &mut Fluid) {
+        let (i0, j0) = self.start;
+        let f = self.factor;
+        let n = coarse.num_y;
+        let fine = &self.fluid;
+        let fine_n = fine.num_y;
+        let inv = 1.0 / f as f32;
+
+        // Skip a one cell margin where the fine solution is dominated by its boundary
+        for ci in i0 + 1..i0 + self.size - 1 {
+            for cj in j0 + 1..j0 + self.size - 1 {
+                let fi0 = (ci - i0) * f + 1;
+                let fj0 = (cj - j0) * f + 1;
+                let cell = ci * n + cj;
+
+                let mut m = 0.0;
+                let mut p = 0.0;
+                for a in 0..f {
+                    for b in 0..f {
+                        m += fine.m[(fi0 + a) * fine_n + fj0 + b];
+                        p += fine.p[(fi0 + a) * fine_n + fj0 + b];
                     }
-                } else if fluid.s[i * n + j] == 0.0 {
-                    color[0..=2].fill(0);
                 }
-                let x = f32::floor(self.c_x((i as f32 - 1.0) * h, self.scale)) as usize;
-                let y = f32::floor(self.c_y((j as f32 + 1.0) * h, self.height, self.scale)) as usize;
-                for yi in y..y + cy {
-                    let mut p = 4 * (yi * self.width as usize + x);
-                    for _ in 0..cx {
-                        p += 4;
-                        if p <= render_buffer.len() {
-                            render_buffer[p - 4..p].copy_from_slice(&color);
-                        }
-                    }
+                coarse.m[cell] = m * inv * inv;
+                coarse.p[cell] = p * inv * inv;
+
+                if coarse.s[cell] != 0.0 && coarse.s[cell - n] != 0.0 {
+                    let u: f32 = (0..f).map(|b| fine.u[fi0 * fine_n + fj0 + b]).sum();
+                    coarse.u[cell] = u * inv;
+                }
+                if coarse.s[cell] != 0.0 && coarse.s[cell - 1] != 0.0 {
+                    let v: f32 = (0..f).map(|a| fine.v[(fi0 + a) * fine_n + fj0]).sum();
+                    coarse.v[cell] = v * inv;
                 }
             }
         }
     }
-
-    pub fn c_x(&self, x: f32, scale: f32) -> f32 {
-        x * scale
-    }
-
-    pub fn c_y(&self, y: f32, height: f32, scale: f32) -> f32 {
-        height - y * scale
-    }
 }
 
+#[derive(Clone)]
 pub struct Fluid {
     pub density: f32,
     pub num_x: usize,
@@ -308,6 +2785,16 @@ pub struct Fluid {
     pub s: Vec<f32>,
     pub m: Vec<f32>,
     pub new_m: Vec<f32>,
+    // Drag coefficient of porous cells, 0 for free flow
+    pub resistance: Vec<f32>,
+    // Velocity the cell is driven to after every projection, None leaves the cell free
+    pub forced: Vec<Option<Vec2>>,
+    // Per cell over-relaxation used by the pressure solve instead of the scalar factor when set
+    pub relaxation: Option<Vec<f32>>,
+    // Linear damping of the horizontal and vertical velocity in 1/s, applied to all fluid faces.
+    // Unequal values make the flow prefer one direction
+    pub damping_x: f32,
+    pub damping_y: f32,
 }
 
 impl Fluid {
@@ -327,65 +2814,703 @@ impl Fluid {
             s: vec![0.0; num_cells],
             m: vec![1.0; num_cells], // Initially filled with 1.0
             new_m: vec![0.0; num_cells],
+            resistance: vec![0.0; num_cells],
+            forced: vec![None; num_cells],
+            relaxation: None,
+            damping_x: 0.0,
+            damping_y: 0.0,
+        }
+    }
+
+    pub fn state(&self) -> FluidState {
+        FluidState {
+            num_x: self.num_x,
+            num_y: self.num_y,
+            h: self.h,
+            density: self.density,
+            u: self.u.clone(),
+            v: self.v.clone(),
+            p: self.p.clone(),
+            s: self.s.clone(),
+            m: self.m.clone(),
+            resistance: self.resistance.clone(),
+            forced: self.forced.iter().map(|forced| forced.map(|velocity| velocity.to_array())).collect(),
+        }
+    }
+
+    // Overwrites the fields with a saved state of a grid of the same size
+    pub fn restore(&mut self, state: &FluidState) -> io::Result<()> {
+        let cells = self.num_cells;
+        let fields = [&state.u, &state.v, &state.p, &state.s, &state.m, &state.resistance];
+        if state.num_x != self.num_x || state.num_y != self.num_y
+            || fields.iter().any(|field| field.len() != cells) || state.forced.len() != cells {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "saved fluid state doesn't match the {} x {} grid", self.num_x, self.num_y)));
         }
+        self.h = state.h;
+        self.density = state.density;
+        self.u.copy_from_slice(&state.u);
+        self.v.copy_from_slice(&state.v);
+        self.p.copy_from_slice(&state.p);
+        self.s.copy_from_slice(&state.s);
+        self.m.copy_from_slice(&state.m);
+        self.resistance.copy_from_slice(&state.resistance);
+        for (forced, saved) in self.forced.iter_mut().zip(&state.forced) {
+            *forced = saved.map(Vec2::from_array);
+        }
+        Ok(())
     }
 
-    fn integrate(&mut self, dt: f32, gravity: f32) {
+    fn integrate(&mut self, dt: f32, gravity: f32, scheme: TimeIntegration) {
         let n = self.num_y;
+        // Darcy-like drag in porous cells, plus the directional damping between fluid cells.
+        // Gravity acts on the v faces between fluid cells and is integrated together with the drag
         for i in 1..self.num_x {
-            for j in 1..(self.num_y - 1) {
-                if self.s[i * n + j] != 0.0 && self.s[i * n + j - 1] != 0.0 {
-                    self.v[i * n + j] += gravity * dt;
+            for j in 1..self.num_y {
+                let fluid = self.s[i * n + j] != 0.0;
+                let mut ku = 0.5 * (self.resistance[(i - 1) * n + j] + self.resistance[i * n + j]);
+                if fluid && self.s[(i - 1) * n + j] != 0.0 {
+                    ku += self.damping_x;
+                }
+                if ku > 0.0 {
+                    self.u[i * n + j] *= scheme.drag_factor(ku, dt);
+                }
+                let mut kv = 0.5 * (self.resistance[i * n + j - 1] + self.resistance[i * n + j]);
+                let open = fluid && self.s[i * n + j - 1] != 0.0;
+                if open {
+                    kv += self.damping_y;
+                }
+                let force = if open && j < self.num_y - 1 { gravity } else { 0.0 };
+                if kv > 0.0 {
+                    self.v[i * n + j] = scheme.drag_factor(kv, dt) * self.v[i * n + j] + scheme.force_gain(kv, dt) * force * dt;
+                } else if force != 0.0 {
+                    self.v[i * n + j] += force * dt;
                 }
             }
         }
     }
 
-    fn solve_incompressibility(&mut self, dt: f32, iterations: i32, over_relaxation: f32) {
+    // Implicit viscous diffusion, solving (1 - viscosity * dt * laplacian) u' = u with Gauss-Seidel
+    // sweeps so any viscosity is stable. The velocity before diffusion is kept in the scratch
+    // buffers. Solid faces act as no-slip walls moving with their prescribed velocity
+    pub fn diffuse_velocity(&mut self, dt: f32, viscosity: f32) {
+        if viscosity <= 0.0 {
+            return;
+        }
         let n = self.num_y;
-        let cp = self.density * self.h / dt;
-        for _ in 0..iterations {
+        let a = viscosity * dt / (self.h * self.h);
+        self.new_u.copy_from_slice(&self.u);
+        self.new_v.copy_from_slice(&self.v);
+
+        for _ in 0..VISCOSITY_ITERATIONS {
             for i in 1..self.num_x - 1 {
                 for j in 1..self.num_y - 1 {
                     if self.s[i * n + j] == 0.0 {
                         continue;
                     }
+                    if i > 1 && self.s[(i - 1) * n + j] != 0.0 {
+                        let neighbours = self.u[(i - 1) * n + j] + self.u[(i + 1) * n + j] + self.u[i * n + j - 1] + self.u[i * n + j + 1];
+                        self.u[i * n + j] = (self.new_u[i * n + j] + a * neighbours) / (1.0 + 4.0 * a);
+                    }
+                    if j > 1 && self.s[i * n + j - 1] != 0.0 {
+                        let neighbours = self.v[(i - 1) * n + j] + self.v[(i + 1) * n + j] + self.v[i * n + j - 1] + self.v[i * n + j + 1];
+                        self.v[i * n + j] = (self.new_v[i * n + j] + a * neighbours) / (1.0 + 4.0 * a);
+                    }
+                }
+            }
+        }
+    }
 
-                    let sx0 = self.s[(i - 1) * n + j];
-                    let sx1 = self.s[(i + 1) * n + j];
-                    let sy0 = self.s[i * n + j - 1];
-                    let sy1 = self.s[i * n + j + 1];
-                    let s = sx0 + sx1 + sy0 + sy1;
-                    if s == 0.0 {
-                        continue;
+    // Pushes the flow towards rotation around local curl maxima with the force
+    // strength * h * (N x curl), N being the normalized gradient of the curl magnitude
+    pub fn apply_vorticity_confinement(&mut self, dt: f32, strength: f32) {
+        if strength <= 0.0 {
+            return;
+        }
+        let n = self.num_y;
+        let h = self.h;
+        let u_center = |i: usize, j: usize| 0.5 * (self.u[i * n + j] + self.u[(i + 1) * n + j]);
+        let v_center = |i: usize, j: usize| 0.5 * (self.v[i * n + j] + self.v[i * n + j + 1]);
+
+        // Curl at cell centers, from central differences of the cell centered velocity
+        let mut curl = vec![0.0; self.num_cells];
+        for i in 2..self.num_x - 2 {
+            for j in 2..self.num_y - 2 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let dv_dx = v_center(i + 1, j) - v_center(i - 1, j);
+                let du_dy = u_center(i, j + 1) - u_center(i, j - 1);
+                curl[i * n + j] = (dv_dx - du_dy) / (2.0 * h);
+            }
+        }
+
+        let mut force = vec![Vec2::ZERO; self.num_cells];
+        for i in 3..self.num_x - 3 {
+            for j in 3..self.num_y - 3 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let gradient = Vec2::new(
+                    curl[(i + 1) * n + j].abs() - curl[(i - 1) * n + j].abs(),
+                    curl[i * n + j + 1].abs() - curl[i * n + j - 1].abs(),
+                );
+                let length = gradient.length();
+                if length < 1e-6 {
+                    continue;
+                }
+                let normal = gradient / length;
+                let omega = curl[i * n + j];
+                force[i * n + j] = strength * h * Vec2::new(normal.y * omega, -normal.x * omega);
+            }
+        }
+
+        // Each face takes the average of the forces of the fluid cells on either side
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                if self.s[(i - 1) * n + j] != 0.0 {
+                    self.u[i * n + j] += 0.5 * dt * (force[(i - 1) * n + j].x + force[i * n + j].x);
+                }
+                if self.s[i * n + j - 1] != 0.0 {
+                    self.v[i * n + j] += 0.5 * dt * (force[i * n + j - 1].y + force[i * n + j].y);
+                }
+            }
+        }
+    }
+
+    // Red-black Gauss-Seidel: cells with even i + j are relaxed first, then the odd ones. Cells of
+    // one color share no faces, so each half sweep computes its corrections from the same velocities
+    // and applies them column by column in parallel. When residuals is given it is filled with the
+    // max divergence after every sweep
+    fn solve_incompressibility(&mut self, dt: f32, iterations: i32, over_relaxation: f32, mut residuals: Option<&mut Vec<f32>>) {
+        let n = self.num_y;
+        let num_x = self.num_x;
+        let cp = self.density * self.h / dt;
+        if let Some(residuals) = residuals.as_deref_mut() {
+            residuals.clear();
+        }
+        let mut correction = vec![0.0; self.num_cells];
+        for _ in 0..iterations {
+            for color in 0..2 {
+                self.pressure_corrections(color, over_relaxation, &mut correction);
+
+                let s = &self.s;
+                let correction = &correction;
+                // Each face takes the correction of whichever of its two cells was relaxed
+                self.u.par_chunks_mut(n).enumerate().skip(1).for_each(|(i, column)| {
+                    for (j, u) in column.iter_mut().enumerate().take(n - 1).skip(1) {
+                        *u += s[i * n + j] * correction[(i - 1) * n + j] - s[(i - 1) * n + j] * correction[i * n + j];
                     }
+                });
+                self.v.par_chunks_mut(n).enumerate().take(num_x - 1).skip(1).for_each(|(i, column)| {
+                    for (j, v) in column.iter_mut().enumerate().skip(1) {
+                        *v += s[i * n + j] * correction[i * n + j - 1] - s[i * n + j - 1] * correction[i * n + j];
+                    }
+                });
+                for (p, correction) in self.p.iter_mut().zip(correction) {
+                    *p += cp * correction;
+                }
+            }
 
-                    let div = self.u[(i + 1) * n + j] -
-                                   self.u[i * n + j] +
-                                   self.v[i * n + j + 1] -
-                                   self.v[i * n + j];
-                    let p = (-div / s) * over_relaxation;
-                    self.p[i * n + j] += cp * p;
+            if let Some(residuals) = residuals.as_deref_mut() {
+                residuals.push(self.max_divergence());
+            }
+        }
+    }
+
+    // Over-relaxed velocity correction of every fluid cell of one color, 0 elsewhere
+    fn pressure_corrections(&self, color: usize, over_relaxation: f32, correction: &mut [f32]) {
+        let n = self.num_y;
+        let num_x = self.num_x;
+        let (s, u, v) = (&self.s, &self.u, &self.v);
+        let relaxation = self.relaxation.as_deref();
+
+        correction.par_chunks_mut(n).enumerate().for_each(|(i, column)| {
+            column.fill(0.0);
+            if i == 0 || i >= num_x - 1 {
+                return;
+            }
+            for (j, correction) in column.iter_mut().enumerate().take(n - 1).skip(1) {
+                if (i + j) % 2 != color || s[i * n + j] == 0.0 {
+                    continue;
+                }
+
+                let sx0 = s[(i - 1) * n + j];
+                let sx1 = s[(i + 1) * n + j];
+                let sy0 = s[i * n + j - 1];
+                let sy1 = s[i * n + j + 1];
+                let s = sx0 + sx1 + sy0 + sy1;
+                if s == 0.0 {
+                    continue;
+                }
+
+                let div = u[(i + 1) * n + j] -
+                               u[i * n + j] +
+                               v[i * n + j + 1] -
+                               v[i * n + j];
+                let omega = match relaxation {
+                    Some(relaxation) => relaxation[i * n + j],
+                    None => over_relaxation,
+                };
+                *correction = (-div / s) * omega;
+            }
+        });
+    }
+
+    // Keeps the amount of dye 1 - m within [0, max_smoke], clear fluid is left alone
+    fn clamp_smoke(&mut self, max_smoke: f32) {
+        for m in self.m.iter_mut() {
+            *m = m.clamp(1.0 - max_smoke, 1.0);
+        }
+    }
+
+    fn apply_force_field(&mut self, dt: f32, force_field: &mut ForceField) {
+        let n = self.num_y;
+        let h = self.h;
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                if self.s[(i - 1) * n + j] != 0.0 {
+                    self.u[i * n + j] += force_field(i, j, i as f32 * h, (j as f32 + 0.5) * h).x * dt;
+                }
+                if self.s[i * n + j - 1] != 0.0 {
+                    self.v[i * n + j] += force_field(i, j, (i as f32 + 0.5) * h, j as f32 * h).y * dt;
+                }
+            }
+        }
+    }
+
+    // Replaces the velocity with the average of itself and its mirror image. The velocity
+    // component across the axis flips sign under the mirror, the one along it doesn't.
+    fn symmetrize(&mut self, axis: SymmetryAxis) {
+        let n = self.num_y;
+        let num_x = self.num_x;
+        match axis {
+            SymmetryAxis::Horizontal => {
+                for i in 0..num_x {
+                    for j in 0..n / 2 {
+                        let (a, b) = (i * n + j, i * n + n - 1 - j);
+                        let u = 0.5 * (self.u[a] + self.u[b]);
+                        self.u[a] = u;
+                        self.u[b] = u;
+                    }
+                    // Face j mirrors onto face n - j, the middle face must carry no flow
+                    for j in 1..=n / 2 {
+                        let (a, b) = (i * n + j, i * n + n - j);
+                        let v = if a == b { 0.0 } else { 0.5 * (self.v[a] - self.v[b]) };
+                        self.v[a] = v;
+                        self.v[b] = -v;
+                    }
+                }
+            }
+            SymmetryAxis::Vertical => {
+                for j in 0..n {
+                    for i in 0..num_x / 2 {
+                        let (a, b) = (i * n + j, (num_x - 1 - i) * n + j);
+                        let v = 0.5 * (self.v[a] + self.v[b]);
+                        self.v[a] = v;
+                        self.v[b] = v;
+                    }
+                    for i in 1..=num_x / 2 {
+                        let (a, b) = (i * n + j, (num_x - i) * n + j);
+                        let u = if a == b { 0.0 } else { 0.5 * (self.u[a] - self.u[b]) };
+                        self.u[a] = u;
+                        self.u[b] = -u;
+                    }
+                }
+            }
+        }
+    }
+
+    // Relaxation scaled by the share of open neighbours, plain Gauss-Seidel against walls and the
+    // full factor in open fluid where over-relaxation pays off
+    fn set_relaxation_from_solids(&mut self, over_relaxation: f32) {
+        let n = self.num_y;
+        let mut relaxation = self.relaxation.take().unwrap_or_default();
+        relaxation.clear();
+        relaxation.resize(self.num_cells, over_relaxation);
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                let open = self.s[(i - 1) * n + j] + self.s[(i + 1) * n + j] + self.s[i * n + j - 1] + self.s[i * n + j + 1];
+                relaxation[i * n + j] = 1.0 + (over_relaxation - 1.0) * open / 4.0;
+            }
+        }
+        self.relaxation = Some(relaxation);
+    }
+
+    // Labels every fluid cell with the 4-connected region it belongs to, starting at 1, solids get
+    // 0. Returns the labels and the region count.
+    fn label_fluid_regions(&self) -> (Vec<u32>, usize) {
+        let n = self.num_y;
+        let mut labels = vec![0; self.num_cells];
+        let mut regions = 0;
+        let mut stack = Vec::new();
+
+        for start in 0..self.num_cells {
+            if self.s[start] == 0.0 || labels[start] != 0 {
+                continue;
+            }
+            regions += 1;
+            labels[start] = regions as u32;
+            stack.push(start);
+
+            while let Some(cell) = stack.pop() {
+                let (i, j) = (cell / n, cell % n);
+                let neighbours = [
+                    (i > 0).then(|| cell - n),
+                    (i + 1 < self.num_x).then(|| cell + n),
+                    (j > 0).then(|| cell - 1),
+                    (j + 1 < n).then(|| cell + 1),
+                ];
+                for neighbour in neighbours.into_iter().flatten() {
+                    if self.s[neighbour] != 0.0 && labels[neighbour] == 0 {
+                        labels[neighbour] = regions as u32;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+
+        (labels, regions)
+    }
+
+    // Stops the flow everywhere except in the largest fluid region
+    fn clear_all_but_largest_region(&mut self, labels: &[u32], regions: usize) {
+        let mut sizes = vec![0usize; regions + 1];
+        for &label in labels.iter().filter(|&&label| label != 0) {
+            sizes[label as usize] += 1;
+        }
+        let largest = (1..=regions).max_by_key(|&label| sizes[label]).unwrap_or(1) as u32;
+
+        let n = self.num_y;
+        for (cell, &label) in labels.iter().enumerate() {
+            if label == 0 || label == largest {
+                continue;
+            }
+            self.p[cell] = 0.0;
+            self.u[cell] = 0.0;
+            self.v[cell] = 0.0;
+            // Faces on the right and top edges of the cell belong to its neighbours
+            if cell + n < self.num_cells {
+                self.u[cell + n] = 0.0;
+            }
+            if (cell + 1) % n != 0 {
+                self.v[cell + 1] = 0.0;
+            }
+        }
+    }
+
+    // Overwrites the smoke in fluid cells with a pattern, velocities and solids are left alone
+    pub fn set_smoke_pattern(&mut self, pattern: SmokePattern) {
+        const CHECKER_CELLS: usize = 10;
+        const BLOB_RADIUS: f32 = 0.2;
+
+        let n = self.num_y;
+        let h = self.h;
+        let center = 0.5 * h * Vec2::new(self.num_x as f32, self.num_y as f32);
+        for i in 0..self.num_x {
+            for j in 0..self.num_y {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let pos = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                self.m[i * n + j] = match pattern {
+                    SmokePattern::Checkerboard => ((i / CHECKER_CELLS + j / CHECKER_CELLS) % 2) as f32,
+                    SmokePattern::Gradient => i as f32 / (self.num_x - 1) as f32,
+                    SmokePattern::Blob => if pos.distance(center) < BLOB_RADIUS { 0.0 } else { 1.0 },
+                    SmokePattern::Clear => 1.0,
+                };
+            }
+        }
+    }
+
+    // Moves smoke towards the clear value of 1, keeping the given fraction of its density
+    fn decay_smoke(&mut self, keep: f32) {
+        for (m, &s) in self.m.iter_mut().zip(self.s.iter()) {
+            if s != 0.0 {
+                *m = 1.0 - (1.0 - *m) * keep;
+            }
+        }
+    }
+
+    // Overrides the faces of forced cells, leaving faces shared with solids alone
+    fn apply_forced_velocities(&mut self) {
+        let n = self.num_y;
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                let Some(velocity) = self.forced[i * n + j] else {
+                    continue;
+                };
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+
+                if self.s[(i - 1) * n + j] != 0.0 {
+                    self.u[i * n + j] = velocity.x;
+                }
+                if self.s[(i + 1) * n + j] != 0.0 {
+                    self.u[(i + 1) * n + j] = velocity.x;
+                }
+                if self.s[i * n + j - 1] != 0.0 {
+                    self.v[i * n + j] = velocity.y;
+                }
+                if self.s[i * n + j + 1] != 0.0 {
+                    self.v[i * n + j + 1] = velocity.y;
+                }
+            }
+        }
+    }
+
+    // Cells travelled per step by the flow at the cell center, |vel| * dt / h
+    fn courant(&self, i: usize, j: usize, dt: f32) -> f32 {
+        let n = self.num_y;
+        let u = 0.5 * (self.u[i * n + j] + self.u[(i + 1).min(self.num_x - 1) * n + j]);
+        let v = 0.5 * (self.v[i * n + j] + self.v[i * n + (j + 1).min(n - 1)]);
+        Vec2::new(u, v).length() * dt / self.h
+    }
+
+    pub fn max_courant(&self, dt: f32) -> f32 {
+        let n = self.num_y;
+        (1..self.num_x - 1)
+            .flat_map(|i| (1..n - 1).map(move |j| (i, j)))
+            .filter(|&(i, j)| self.s[i * n + j] != 0.0)
+            .map(|(i, j)| self.courant(i, j, dt))
+            .fold(0.0, f32::max)
+    }
+
+    // Net outflow of a cell, as corrected by the pressure solve
+    fn divergence(&self, i: usize, j: usize) -> f32 {
+        let n = self.num_y;
+        self.u[(i + 1) * n + j] - self.u[i * n + j] + self.v[i * n + j + 1] - self.v[i * n + j]
+    }
+
+    // Iterates the divergence magnitude of every interior fluid cell
+    fn fluid_divergences(&self) -> impl Iterator<Item = f32> + '_ {
+        let n = self.num_y;
+        (1..self.num_x - 1)
+            .flat_map(move |i| (1..self.num_y - 1).map(move |j| (i, j)))
+            .filter(move |&(i, j)| self.s[i * n + j] != 0.0)
+            .map(move |(i, j)| self.divergence(i, j).abs())
+    }
+
+    // Strain rate magnitude sqrt(2 (ux^2 + vy^2) + (uy + vx)^2) at the cell centers, 0 in solids
+    // and the boundary cells. Cross derivatives use central differences of cell centered velocities.
+    pub fn strain_rate(&self) -> Vec<f32> {
+        let n = self.num_y;
+        let h = self.h;
+        let u_center = |i: usize, j: usize| 0.5 * (self.u[i * n + j] + self.u[(i + 1) * n + j]);
+        let v_center = |i: usize, j: usize| 0.5 * (self.v[i * n + j] + self.v[i * n + j + 1]);
+        let mut rate = vec![0.0; self.num_cells];
+
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let ux = (self.u[(i + 1) * n + j] - self.u[i * n + j]) / h;
+                let vy = (self.v[i * n + j + 1] - self.v[i * n + j]) / h;
+                let uy = (u_center(i, j + 1) - u_center(i, j - 1)) / (2.0 * h);
+                let vx = (v_center(i + 1, j) - v_center(i - 1, j)) / (2.0 * h);
+                rate[i * n + j] = f32::sqrt(2.0 * (ux * ux + vy * vy) + (uy + vx) * (uy + vx));
+            }
+        }
+        rate
+    }
+
+    // |divergence| of the interior cells in rows of num_x - 2, top row first, solids are 0
+    pub fn divergence_field(&self) -> Vec<f32> {
+        let n = self.num_y;
+        let width = self.num_x - 2;
+        let height = self.num_y - 2;
+        let mut field = vec![0.0; width * height];
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[i * n + j] != 0.0 {
+                    field[(height - j) * width + i - 1] = self.divergence(i, j).abs();
+                }
+            }
+        }
+        field
+    }
+
+    pub fn max_divergence(&self) -> f32 {
+        self.fluid_divergences().fold(0.0, f32::max)
+    }
+
+    pub fn divergence_histogram(&self, bins: usize) -> Vec<u32> {
+        let bins = bins.max(1);
+        let max = self.max_divergence();
+        let mut histogram = vec![0; bins];
+
+        for div in self.fluid_divergences() {
+            let bin = if max > 0.0 { (div / max * bins as f32) as usize } else { 0 };
+            histogram[bin.min(bins - 1)] += 1;
+        }
+
+        histogram
+    }
+
+    fn extrapolate(&mut self) {
+        let n = self.num_y;
+        for i in 0..self.num_x {
+            self.u[i * n] = self.u[i * n + 1];
+            self.u[i * n + n - 1] = self.u[i * n + n - 2];
+        }
+        for j in 0..self.num_y {
+            self.v[j] = self.v[n + j];
+            self.v[(self.num_x - 1) * n + j] = self.v[(self.num_x - 2) * n + j];
+        }
+
+        // A corner face lies in both a ghost row and a ghost column, so it takes the average of its
+        // horizontal and vertical neighbours. Faces on the last u column and the last v row sit on
+        // the domain boundary rather than outside of it and keep their edge copy.
+        let (last_i, last_j) = (self.num_x - 1, n - 1);
+        for (j, nj) in [(0, 1), (last_j, last_j - 1)] {
+            self.u[j] = 0.5 * (self.u[n + j] + self.u[nj]);
+        }
+        for (i, ni) in [(0, 1), (last_i, last_i - 1)] {
+            self.v[i * n] = 0.5 * (self.v[ni * n] + self.v[i * n + 1]);
+        }
+    }
+
+    // Sets the velocities on faces inside obstacles by mirroring the tangential velocity of the
+    // neighboring fluid faces (no-slip), so advection near solids samples a consistent boundary
+    // rather than whatever was left from rasterization. Domain walls are left to extrapolate.
+    fn apply_ghost_boundaries(&mut self) {
+        let n = self.num_y;
+
+        for i in 2..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[(i - 1) * n + j] != 0.0 || self.s[i * n + j] != 0.0 {
+                    continue;
+                }
+                let mut sum = 0.0;
+                let mut count = 0.0;
+                for jj in [j - 1, j + 1] {
+                    if self.s[(i - 1) * n + jj] != 0.0 && self.s[i * n + jj] != 0.0 {
+                        sum -= self.u[i * n + jj];
+                        count += 1.0;
+                    }
+                }
+                self.u[i * n + j] = if count > 0.0 { sum / count } else { 0.0 };
+            }
+        }
+
+        for i in 1..self.num_x - 1 {
+            for j in 2..self.num_y - 1 {
+                if self.s[i * n + j - 1] != 0.0 || self.s[i * n + j] != 0.0 {
+                    continue;
+                }
+                let mut sum = 0.0;
+                let mut count = 0.0;
+                for ii in [i - 1, i + 1] {
+                    if self.s[ii * n + j - 1] != 0.0 && self.s[ii * n + j] != 0.0 {
+                        sum -= self.v[ii * n + j];
+                        count += 1.0;
+                    }
+                }
+                self.v[i * n + j] = if count > 0.0 { sum / count } else { 0.0 };
+            }
+        }
+    }
+
+    // White noise averaged along the streamline through each interior cell center, following the
+    // normalized velocity in half cell steps each way. The noise is hashed from the seed so equal
+    // seeds give equal textures. Returns rows of num_x - 2 values, top row first, solids are 0
+    pub fn line_integral_convolution(&self, seed: u64, kernel_length: usize) -> Vec<f32> {
+        let n = self.num_y;
+        let h = self.h;
+        let width = self.num_x - 2;
+        let height = self.num_y - 2;
+        let noise: Vec<f32> = (0..self.num_cells).map(|cell| hash_noise(seed, cell)).collect();
+        let mut lic = vec![0.0; width * height];
 
-                    self.u[i * n + j] -= sx0 * p;
-                    self.u[(i + 1) * n + j] += sx1 * p;
-                    self.v[i * n + j] -= sy0 * p;
-                    self.v[i * n + j + 1] += sy1 * p;
+        for i in 1..self.num_x - 1 {
+            for j in 1..self.num_y - 1 {
+                if self.s[i * n + j] == 0.0 {
+                    continue;
+                }
+                let start = Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+                let mut sum = noise[i * n + j];
+                let mut count = 1.0;
+                for direction in [1.0, -1.0] {
+                    let mut pos = start;
+                    for _ in 0..kernel_length {
+                        let vel = Vec2::new(self.sample_field(pos.x, pos.y, Field::U), self.sample_field(pos.x, pos.y, Field::V));
+                        let Some(dir) = vel.try_normalize() else {
+                            break;
+                        };
+                        pos += direction * 0.5 * h * dir;
+                        let ci = f32::floor(pos.x / h) as usize;
+                        let cj = f32::floor(pos.y / h) as usize;
+                        if ci < 1 || ci >= self.num_x - 1 || cj < 1 || cj >= self.num_y - 1 || self.s[ci * n + cj] == 0.0 {
+                            break;
+                        }
+                        sum += noise[ci * n + cj];
+                        count += 1.0;
+                    }
                 }
+                lic[(height - j) * width + i - 1] = sum / count;
             }
         }
+        lic
     }
 
-    fn extrapolate(&mut self) {
+    // Stream function at the cell corners, psi[i * n + j] at (i * h, j * h), with u = dpsi/dy and
+    // v = -dpsi/dx. The boundary of the interior is integrated from the face velocities and the
+    // inside solves the Poisson equation laplace(psi) = -vorticity with the same SOR Gauss-Seidel
+    // sweeps as the pressure solve, starting from the column wise integral of u.
+    pub fn stream_function(&self) -> Vec<f32> {
         let n = self.num_y;
-        for i in 0..self.num_x {
-            self.u[i * n] = self.u[i * n + 1];
-            self.u[i * n + n - 1] = self.u[i * n + n - 2];
+        let h = self.h;
+        let (left, right) = (1, self.num_x - 1);
+        let (bottom, top) = (1, self.num_y - 1);
+        let mut psi = vec![0.0; self.num_cells];
+
+        for i in left..=right {
+            if i > left {
+                psi[i * n + bottom] = psi[(i - 1) * n + bottom] - h * self.v[(i - 1) * n + bottom];
+            }
+            for j in bottom + 1..=top {
+                psi[i * n + j] = psi[i * n + j - 1] + h * self.u[i * n + j - 1];
+            }
         }
-        for j in 0..self.num_y {
-            self.v[j] = self.v[n + j];
-            self.v[(self.num_x - 1) * n + j] = self.v[(self.num_x - 2) * n + j];
+
+        // The top row is reached from the left wall rather than up each column
+        for i in left + 1..=right {
+            psi[i * n + top] = psi[(i - 1) * n + top] - h * self.v[(i - 1) * n + top];
+        }
+
+        for _ in 0..STREAM_FUNCTION_ITERATIONS {
+            for i in left + 1..right {
+                for j in bottom + 1..top {
+                    let vorticity = (self.v[i * n + j] - self.v[(i - 1) * n + j] - self.u[i * n + j] + self.u[i * n + j - 1]) / h;
+                    let neighbours = psi[(i - 1) * n + j] + psi[(i + 1) * n + j] + psi[i * n + j - 1] + psi[i * n + j + 1];
+                    let target = 0.25 * (neighbours + h * h * vorticity);
+                    psi[i * n + j] += OVERRELAXATION * (target - psi[i * n + j]);
+                }
+            }
         }
+
+        psi
+    }
+
+    // Bilinear interpolation of the cell centered pressure at a point in simulation coordinates
+    pub fn sample_pressure(&self, x: f32, y: f32) -> f32 {
+        self.sample_field(x, y, Field::P)
+    }
+
+    // Central difference of the interpolated velocity over one cell each way
+    pub fn sample_vorticity(&self, x: f32, y: f32) -> f32 {
+        let h = self.h;
+        let dv_dx = self.sample_field(x + h, y, Field::V) - self.sample_field(x - h, y, Field::V);
+        let du_dy = self.sample_field(x, y + h, Field::U) - self.sample_field(x, y - h, Field::U);
+        (dv_dx - du_dy) / (2.0 * h)
     }
 
     pub fn sample_field(&self, x: f32, y: f32, field: Field) -> f32 {
@@ -413,6 +3538,11 @@ impl Fluid {
                 dy = h2;
                 &self.m
             }
+            Field::P => {
+                dx = h2;
+                dy = h2;
+                &self.p
+            }
         };
 
         let x0 = f32::min(f32::floor((x - dx) * h1), (self.num_x - 1) as f32) as usize;
@@ -451,15 +3581,20 @@ impl Fluid {
     }
 
     fn advect_vel(&mut self, dt: f32) {
-        self.new_u.copy_from_slice(&self.u);
-        self.new_v.copy_from_slice(&self.v);
-
         let n = self.num_y;
         let h = self.h;
         let h2 = 0.5 * h;
 
+        // Every face is written, either advected or carried over, so the swap needs no copy first.
+        // Both components read the cell below or to the left, which the boundary row and column lack
         for i in 0..self.num_x {
             for j in 0..self.num_y {
+                self.new_u[i * n + j] = self.u[i * n + j];
+                self.new_v[i * n + j] = self.v[i * n + j];
+                if i == 0 || j == 0 {
+                    continue;
+                }
+
                 // u component
                 if self.s[i * n + j] != 0.0 &&
                     self.s[(i - 1) * n + j] != 0.0 &&
@@ -491,30 +3626,66 @@ impl Fluid {
             }
         }
 
-        self.u.copy_from_slice(&self.new_u);
-        self.v.copy_from_slice(&self.new_v);
+        // The new buffers become current, the old ones are overwritten next step
+        std::mem::swap(&mut self.u, &mut self.new_u);
+        std::mem::swap(&mut self.v, &mut self.new_v);
     }
 
-    fn advect_smoke(&mut self, dt: f32) {
-        self.new_m.copy_from_slice(&self.m);
+    // Bilinear smoke interpolation over the fluid cells only, None when all four are solid
+    fn sample_fluid_smoke(&self, x: f32, y: f32) -> Option<f32> {
+        let n = self.num_y;
+        let h = self.h;
+        let h2 = 0.5 * h;
+
+        let x = x.clamp(h, self.num_x as f32 * h) - h2;
+        let y = y.clamp(h, self.num_y as f32 * h) - h2;
+        let x0 = usize::min(f32::floor(x / h) as usize, self.num_x - 1);
+        let y0 = usize::min(f32::floor(y / h) as usize, self.num_y - 1);
+        let x1 = usize::min(x0 + 1, self.num_x - 1);
+        let y1 = usize::min(y0 + 1, self.num_y - 1);
+        let tx = (x - x0 as f32 * h) / h;
+        let ty = (y - y0 as f32 * h) / h;
+
+        let mut total = 0.0;
+        let mut weight = 0.0;
+        for (cell, w) in [
+            (x0 * n + y0, (1.0 - tx) * (1.0 - ty)),
+            (x1 * n + y0, tx * (1.0 - ty)),
+            (x1 * n + y1, tx * ty),
+            (x0 * n + y1, (1.0 - tx) * ty),
+        ] {
+            if self.s[cell] != 0.0 {
+                total += w * self.m[cell];
+                weight += w;
+            }
+        }
+        if weight > 0.0 { Some(total / weight) } else { None }
+    }
 
+    fn advect_smoke(&mut self, dt: f32, wall_mode: WallSmokeMode) {
         let n = self.num_y;
         let h = self.h;
         let h2 = 0.5 * h;
 
-        for i in 1..self.num_x - 1 {
-            for j in 1..self.num_y - 1 {
-                if self.s[i * n + j] != 0.0 {
+        // Border and solid cells keep their dye, so they are carried over instead of copied up front
+        for i in 0..self.num_x {
+            for j in 0..self.num_y {
+                self.new_m[i * n + j] = self.m[i * n + j];
+                let interior = i > 0 && j > 0 && i < self.num_x - 1 && j < self.num_y - 1;
+                if interior && self.s[i * n + j] != 0.0 {
                     let u = (self.u[i * n + j] + self.u[(i + 1) * n + j]) * 0.5;
                     let v = (self.v[i * n + j] + self.v[i * n + j + 1]) * 0.5;
                     let x = i as f32 * h + h2 - dt * u;
                     let y = j as f32 * h + h2 - dt * v;
 
-                    self.new_m[i * n + j] = self.sample_field(x, y, Field::S);
+                    self.new_m[i * n + j] = match wall_mode {
+                        WallSmokeMode::Absorb => self.sample_field(x, y, Field::S),
+                        WallSmokeMode::Reflect => self.sample_fluid_smoke(x, y).unwrap_or(self.m[i * n + j]),
+                    };
                 }
             }
         }
-        self.m.copy_from_slice(&self.new_m);
+        std::mem::swap(&mut self.m, &mut self.new_m);
     }
 }
 
@@ -523,6 +3694,31 @@ fn color_into_all(color: &mut [u8; 4], val: f32) {
     color[0..=2].fill(val);
 }
 
+// Per cell fields computed once per draw and painted over the shading
+struct Overlays {
+    // Stream function and contour spacing
+    contours: Option<(Vec<f32>, f32)>,
+    // Strain rate magnitude and its maximum
+    shear: Option<(Vec<f32>, f32)>,
+}
+
+// Whether a stream function contour passes through the cell, i.e. its corners fall in different bands
+fn on_stream_contour(psi: &[f32], n: usize, i: usize, j: usize, spacing: f32) -> bool {
+    if (i + 1) * n + j + 1 >= psi.len() || j + 1 >= n {
+        return false;
+    }
+    let bands = [psi[i * n + j], psi[(i + 1) * n + j], psi[i * n + j + 1], psi[(i + 1) * n + j + 1]].map(|value| f32::floor(value / spacing));
+    bands.iter().any(|band| *band != bands[0])
+}
+
+// Uniform value in [0, 1) for a cell, SplitMix64 finalizer over the seed and cell index
+fn hash_noise(seed: u64, cell: usize) -> f32 {
+    let mut z = seed.wrapping_add((cell as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    ((z ^ (z >> 31)) >> 40) as f32 / (1u64 << 24) as f32
+}
+
 fn set_color(dest: &mut [u8; 4], src: &[f32; 3]) {
     dest[0] = f32::floor(src[0]) as u8;
     dest[1] = f32::floor(src[1]) as u8;
@@ -545,3 +3741,779 @@ pub fn get_sci_color(val: f32, min: f32, max: f32) -> [f32; 3] {
     };
     [255. * r, 255. * g, 255. * b]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::render_asset::RenderAssetUsages;
+    use bevy::render::render_resource::{Extent3d, TextureDimension};
+    use bevy::render::texture::{CompressedImageFormats, ImageSampler, ImageType};
+
+    const N: usize = 16;
+
+    // Closed box with a solid block in the middle, noisy velocities between fluid cells and a
+    // uniform velocity on every face touching the block
+    fn divergent_fluid() -> Fluid {
+        let mut f = Fluid::new(1000.0, N, N, 0.01);
+        for i in 1..N - 1 {
+            for j in 1..N - 1 {
+                let block = (6..9).contains(&i) && (6..9).contains(&j);
+                f.s[i * N + j] = if block { 0.0 } else { 1.0 };
+            }
+        }
+        for i in 1..N - 1 {
+            for j in 1..N - 1 {
+                let noise = ((i * 7 + j * 13) % 11) as f32 / 11.0 - 0.5;
+                f.u[i * N + j] = face_velocity(&f, (i - 1) * N + j, i * N + j, noise);
+                f.v[i * N + j] = face_velocity(&f, i * N + j - 1, i * N + j, -noise);
+            }
+        }
+        f
+    }
+
+    fn face_velocity(f: &Fluid, a: usize, b: usize, noise: f32) -> f32 {
+        let on_border = |c: usize| {
+            let (i, j) = (c / N, c % N);
+            i == 0 || j == 0
+        };
+        if on_border(a) || on_border(b) {
+            0.0
+        } else if f.s[a] == 0.0 || f.s[b] == 0.0 {
+            0.25
+        } else {
+            noise
+        }
+    }
+
+    fn max_divergence(f: &Fluid) -> f32 {
+        let mut max = 0.0f32;
+        for i in 1..N - 1 {
+            for j in 1..N - 1 {
+                if f.s[i * N + j] == 0.0 {
+                    continue;
+                }
+                let div = f.u[(i + 1) * N + j] - f.u[i * N + j] + f.v[i * N + j + 1] - f.v[i * N + j];
+                max = max.max(div.abs());
+            }
+        }
+        max
+    }
+
+    #[test]
+    fn projection_reduces_divergence_monotonically() {
+        let initial = max_divergence(&divergent_fluid());
+        let mut previous = initial;
+        for iterations in [5, 20, 80, 320] {
+            let mut f = divergent_fluid();
+            f.solve_incompressibility(1.0 / 60.0, iterations, 1.0, None);
+            let residual = max_divergence(&f);
+            assert!(residual < previous, "{iterations} iterations left {residual}, expected below {previous}");
+            previous = residual;
+        }
+        assert!(previous < initial * 1e-3, "residual {previous} did not approach zero from {initial}");
+    }
+
+    #[test]
+    fn projection_leaves_solid_cells_untouched() {
+        let before = divergent_fluid();
+        let mut f = divergent_fluid();
+        f.solve_incompressibility(1.0 / 60.0, 50, 1.9, None);
+        for i in 0..N {
+            for j in 0..N {
+                let c = i * N + j;
+                if before.s[c] != 0.0 {
+                    continue;
+                }
+                assert_eq!(f.p[c], 0.0, "pressure changed in solid cell ({i}, {j})");
+                assert_eq!(f.u[c], before.u[c], "u changed on a face of solid cell ({i}, {j})");
+                assert_eq!(f.v[c], before.v[c], "v changed on a face of solid cell ({i}, {j})");
+                if i + 1 < N {
+                    assert_eq!(f.u[c + N], before.u[c + N], "u changed on a face of solid cell ({i}, {j})");
+                }
+                if j + 1 < N {
+                    assert_eq!(f.v[c + 1], before.v[c + 1], "v changed on a face of solid cell ({i}, {j})");
+                }
+            }
+        }
+    }
+
+    // Largest per channel difference to a golden frame, which absorbs float differences between
+    // platforms but not a shifted cell or a changed color
+    const GOLDEN_TOLERANCE: u8 = 2;
+
+    // Pressure, optionally darkened where there is no smoke, after some steps with the obstacle in
+    // the middle of the height
+    fn golden_frame(scene_type: SceneType, steps: usize, show_smoke: bool) -> Image {
+        let mut scene = FluidScene::new(160.0, 90.0, scene_type);
+        scene.set_obstacle(Vec2::new(0.5, 0.5), true);
+        scene.show_pressure = true;
+        scene.show_smoke = show_smoke;
+        let (width, height) = (scene.width as u32, scene.height as u32);
+        let mut pixels = vec![0; 4 * (width * height) as usize];
+        for _ in 0..steps {
+            scene.step(1.0 / 60.0, &mut pixels);
+        }
+        let size = Extent3d { width, height, ..default() };
+        Image::new(size, TextureDimension::D2, pixels, TextureFormat::Rgba8Unorm, RenderAssetUsages::MAIN_WORLD)
+    }
+
+    // Compares a frame with tests/goldens/<name>.png. Running the tests with UPDATE_GOLDENS=1
+    // writes the frames as the new goldens instead, after an intentional change to the rendering
+    fn assert_matches_golden(name: &str, frame: Image) {
+        let path = format!("{}/tests/goldens/{name}.png", env!("CARGO_MANIFEST_DIR"));
+        if std::env::var_os("UPDATE_GOLDENS").is_some() {
+            frame.try_into_dynamic().unwrap().save(&path).unwrap();
+            return;
+        }
+
+        let bytes = std::fs::read(&path).unwrap_or_else(|err| panic!("could not read the golden {path}: {err}"));
+        let golden = Image::from_buffer(&bytes, ImageType::Extension("png"), CompressedImageFormats::NONE, false,
+            ImageSampler::Default, RenderAssetUsages::MAIN_WORLD).unwrap();
+        assert_eq!((frame.width(), frame.height()), (golden.width(), golden.height()), "{name} changed size");
+        let width = frame.width() as usize;
+        let differing: Vec<(usize, usize)> = frame.data.chunks_exact(4).zip(golden.data.chunks_exact(4)).enumerate()
+            .filter(|(_, (a, b))| a.iter().zip(*b).any(|(a, b)| a.abs_diff(*b) > GOLDEN_TOLERANCE))
+            .map(|(pixel, _)| (pixel % width, pixel / width))
+            .collect();
+        assert!(differing.is_empty(), "{} pixels of {name} differ from the golden, the first at {:?}", differing.len(), differing[0]);
+    }
+
+    #[test]
+    fn wind_tunnel_renders_like_the_golden() {
+        assert_matches_golden("wind_tunnel", golden_frame(SceneType::WindTunnel, 60, true));
+    }
+
+    #[test]
+    fn tank_renders_like_the_golden() {
+        assert_matches_golden("tank", golden_frame(SceneType::Tank, 60, false));
+    }
+
+    #[test]
+    fn extrapolate_copies_edges_and_averages_corners() {
+        let (num_x, n) = (6, 5);
+        let (last_i, last_j) = (num_x - 1, n - 1);
+        let at = |i: usize, j: usize| i * n + j;
+        let mut f = Fluid::new(1000.0, num_x, n, 0.1);
+        for (k, (u, v)) in f.u.iter_mut().zip(f.v.iter_mut()).enumerate() {
+            *u = k as f32;
+            *v = 100.0 + k as f32;
+        }
+        let (u, v) = (f.u.clone(), f.v.clone());
+
+        let mut expected_u = u.clone();
+        for i in 0..num_x {
+            expected_u[at(i, 0)] = u[at(i, 1)];
+            expected_u[at(i, last_j)] = u[at(i, last_j - 1)];
+        }
+        expected_u[at(0, 0)] = 0.5 * (u[at(1, 1)] + u[at(0, 1)]);
+        expected_u[at(0, last_j)] = 0.5 * (u[at(1, last_j - 1)] + u[at(0, last_j - 1)]);
+
+        let mut expected_v = v.clone();
+        for j in 0..n {
+            expected_v[at(0, j)] = v[at(1, j)];
+            expected_v[at(last_i, j)] = v[at(last_i - 1, j)];
+        }
+        expected_v[at(0, 0)] = 0.5 * (v[at(1, 0)] + v[at(1, 1)]);
+        expected_v[at(last_i, 0)] = 0.5 * (v[at(last_i - 1, 0)] + v[at(last_i - 1, 1)]);
+
+        f.extrapolate();
+        assert_eq!(f.u, expected_u);
+        assert_eq!(f.v, expected_v);
+    }
+
+    // Largest difference between the velocities and their mirror image about the horizontal
+    // centerline, v changes sign under the mirror
+    fn horizontal_asymmetry(f: &Fluid) -> f32 {
+        let n = f.num_y;
+        let mut max = 0.0f32;
+        for i in 0..f.num_x {
+            for j in 0..n {
+                max = max.max((f.u[i * n + j] - f.u[i * n + n - 1 - j]).abs());
+                if j > 0 {
+                    max = max.max((f.v[i * n + j] + f.v[i * n + n - j]).abs());
+                }
+            }
+        }
+        max
+    }
+
+    // Tunnel with the obstacle on the centerline. The odd row count keeps the red-black ordering
+    // of the pressure solve mirror symmetric as well.
+    fn centered_tunnel() -> FluidScene {
+        let mut scene = FluidScene::with_domain(320.0, 180.0, SceneType::WindTunnel, 60, 29);
+        let center = scene.domain_center();
+        scene.set_obstacle(Vec2::new(0.4, center.y), true);
+        scene
+    }
+
+    #[test]
+    fn ghost_boundaries_keep_a_symmetric_wake_symmetric() {
+        let mut scene = centered_tunnel();
+        scene.ghost_boundaries = true;
+        for step in 0..30 {
+            scene.step_simulation(1.0 / 60.0);
+            let asymmetry = horizontal_asymmetry(&scene.fluid);
+            assert!(asymmetry < 1e-4, "step {step} left an asymmetry of {asymmetry}");
+        }
+    }
+
+    #[test]
+    fn strouhal_number_uses_the_measured_inflow() {
+        let mut scene = centered_tunnel();
+        // Wake oscillating at 2 Hz, sampled at 60 Hz
+        for step in 0..120 {
+            let t = step as f32 / 60.0;
+            scene.wake_history.push_back((t, (std::f32::consts::TAU * 2.0 * (t + 0.01)).sin()));
+        }
+        let n = scene.fluid.num_y;
+        let diameter = 2.0 * scene.obstacles()[0].radius;
+
+        scene.fluid.u[n..2 * n].fill(0.5);
+        let strouhal = scene.strouhal_number().unwrap();
+        assert!((strouhal - 2.0 * diameter / 0.5).abs() < 0.01, "St = {strouhal} at half a unit of inflow");
+
+        scene.fluid.u[n..2 * n].fill(0.0);
+        assert_eq!(scene.strouhal_number(), None, "St without inflow");
+    }
+
+    #[test]
+    fn mean_inflow_skips_rows_outside_a_circular_domain() {
+        let mut scene = centered_tunnel();
+        let h = scene.fluid.h;
+        let center = scene.domain_center();
+        scene.set_circular_domain(Some(CircularDomain { center, radius: center.x - h }));
+
+        let n = scene.fluid.num_y;
+        let open: Vec<usize> = (0..n).filter(|&j| scene.fluid.s[n + j] != 0.0).collect();
+        assert!(!open.is_empty() && open.len() < n - 2, "the domain should cut off part of the inlet");
+        for &j in &open {
+            scene.fluid.u[n + j] = 1.0;
+        }
+        assert_eq!(scene.mean_inflow_velocity(), 1.0);
+    }
+
+    // Velocity of face (2, 2) after falling for the given number of steps from rest through a
+    // uniform porous medium, without any projection
+    fn fall_through_porous(scheme: TimeIntegration, resistance: f32, steps: usize, dt: f32) -> f32 {
+        let mut f = Fluid::new(1000.0, 5, 5, 0.1);
+        f.s.fill(1.0);
+        f.resistance.fill(resistance);
+        for _ in 0..steps {
+            f.integrate(dt, GRAVITY, scheme);
+        }
+        f.v[2 * 5 + 2]
+    }
+
+    #[test]
+    fn force_integration_follows_the_analytic_fall() {
+        let dt = 1.0 / 60.0;
+        let t = 60.0 * dt;
+
+        // Without drag both schemes are exact
+        for scheme in [TimeIntegration::Euler, TimeIntegration::Verlet] {
+            let v = fall_through_porous(scheme, 0.0, 60, dt);
+            assert!((v - GRAVITY * t).abs() < 1e-4, "{scheme:?} fell at {v}");
+        }
+
+        // With drag k the velocity approaches g / k as (g / k) * (1 - exp(-k t))
+        let k = 5.0;
+        let exact = GRAVITY / k * (1.0 - f32::exp(-k * t));
+        let euler = (fall_through_porous(TimeIntegration::Euler, k, 60, dt) - exact).abs();
+        let verlet = (fall_through_porous(TimeIntegration::Verlet, k, 60, dt) - exact).abs();
+        assert!(euler < 0.05 * exact.abs(), "Euler is off by {euler}");
+        assert!(verlet < 0.1 * euler, "Verlet is off by {verlet}, Euler by {euler}");
+    }
+
+    #[test]
+    fn stiff_drag_does_not_flip_the_velocity() {
+        let dt = 1.0 / 60.0;
+        // k * dt = 5, past where the trapezoidal factor turns negative
+        let k = 300.0;
+        for scheme in [TimeIntegration::Euler, TimeIntegration::Verlet] {
+            let mut f = Fluid::new(1000.0, 5, 5, 0.1);
+            f.s.fill(1.0);
+            f.resistance.fill(k);
+            f.u.fill(1.0);
+            f.integrate(dt, 0.0, scheme);
+            let u = f.u[2 * 5 + 2];
+            assert!((0.0..1.0).contains(&u), "{scheme:?} left u at {u}");
+        }
+    }
+
+    #[test]
+    fn obstacle_is_circular_in_physical_space() {
+        // Far more cells across than up, so any per-axis scaling would squash the circle
+        let mut scene = FluidScene::with_domain(320.0, 180.0, SceneType::WindTunnel, 90, 40);
+        let (pos, r) = (Vec2::new(0.8, 0.5), 0.2);
+        scene.set_obstacle_radius(r);
+        scene.set_obstacle(pos, true);
+
+        let f = &scene.fluid;
+        let (n, h) = (f.num_y, f.h);
+        for i in 1..f.num_x - 2 {
+            for j in 1..f.num_y - 2 {
+                let d = (Vec2::new((i as f32 + 0.5) * h, (j as f32 + 0.5) * h) - pos).length();
+                let solid = f.s[i * n + j] == 0.0;
+                if d < r - h {
+                    assert!(solid, "cell ({i}, {j}) at distance {d} is outside the obstacle");
+                } else if d > r + h {
+                    assert!(!solid, "cell ({i}, {j}) at distance {d} is inside the obstacle");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn smoke_clamp_bounds_the_dye_not_the_clear_fluid() {
+        let mut f = Fluid::new(1000.0, 3, 3, 0.1);
+        f.m.copy_from_slice(&[1.0, 0.0, 0.75, 1.5, -1.0, 0.5, 1.0, 1.0, 1.0]);
+        f.clamp_smoke(0.5);
+        assert_eq!(f.m, [1.0, 0.5, 0.75, 1.0, 0.5, 0.5, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn obstacle_dragged_onto_the_inlet_stays_clear_of_it() {
+        let mut scene = FluidScene::with_domain(320.0, 180.0, SceneType::WindTunnel, 60, 30);
+        scene.set_obstacle_radius(0.3);
+        let center = scene.domain_center();
+        scene.set_obstacle(Vec2::new(1.0, center.y), true);
+        // Drag to the left edge of the allowed positions, past where the obstacle would cover the inlet
+        for step in 0..=8 {
+            let x = 1.0 - 0.1 * step as f32;
+            scene.set_obstacle(Vec2::new(x, center.y), false);
+            scene.step_simulation(1.0 / 60.0);
+        }
+
+        let f = &scene.fluid;
+        let n = f.num_y;
+        let pos = scene.obstacles()[0].pos;
+        assert!(pos.x >= 2.0 * f.h + 0.3, "obstacle at {pos} covers the inlet");
+        for j in 1..n - 1 {
+            assert_ne!(f.s[n + j], 0.0, "inlet cell {j} turned solid");
+            assert_eq!(f.u[n + j], VELOCITY_IN, "inlet velocity of row {j} was overridden");
+        }
+    }
+
+    #[test]
+    fn enforced_symmetry_keeps_a_symmetric_setup_symmetric() {
+        // An even row count mirrors red cells of the pressure solve onto black ones, so the solve
+        // itself breaks the symmetry
+        let run = |axis: Option<SymmetryAxis>| {
+            let mut scene = FluidScene::with_domain(320.0, 180.0, SceneType::WindTunnel, 60, 30);
+            let center = scene.domain_center();
+            scene.set_obstacle(Vec2::new(0.4, center.y), true);
+            scene.enforce_symmetry = axis;
+            let mut max = 0.0f32;
+            for _ in 0..30 {
+                scene.step_simulation(1.0 / 60.0);
+                max = max.max(horizontal_asymmetry(&scene.fluid));
+            }
+            max
+        };
+
+        let free = run(None);
+        let enforced = run(Some(SymmetryAxis::Horizontal));
+        assert!(free > 1e-2, "the setup stayed symmetric on its own ({free})");
+        assert!(enforced < 1e-4, "enforced symmetry left an asymmetry of {enforced}");
+    }
+
+    #[test]
+    fn sample_pressure_reconstructs_a_linear_field() {
+        let (num_x, n, h) = (8, 6, 0.1);
+        let linear = |x: f32, y: f32| 3.0 + 20.0 * x - 7.0 * y;
+        let mut f = Fluid::new(1000.0, num_x, n, h);
+        for i in 0..num_x {
+            for j in 0..n {
+                f.p[i * n + j] = linear((i as f32 + 0.5) * h, (j as f32 + 0.5) * h);
+            }
+        }
+
+        // Points between the centers of the interior cells, including cell edges and centers
+        for xi in 0..=20 {
+            for yi in 0..=12 {
+                let x = 1.5 * h + xi as f32 * (num_x - 3) as f32 * h / 20.0;
+                let y = 1.5 * h + yi as f32 * (n - 3) as f32 * h / 12.0;
+                let (sampled, exact) = (f.sample_pressure(x, y), linear(x, y));
+                assert!((sampled - exact).abs() < 1e-4, "p({x}, {y}) sampled as {sampled}, expected {exact}");
+            }
+        }
+    }
+
+    #[test]
+    fn history_is_opt_in_and_restores_the_obstacles() {
+        let mut scene = centered_tunnel();
+        scene.step_simulation(1.0 / 60.0);
+        assert_eq!(scene.history_len(), 0, "history was recorded without opting in");
+
+        scene.history_depth = HISTORY_DEPTH;
+        let (pos, fixed) = (scene.obstacles()[0].pos, Vec2::new(0.8, 0.1));
+        scene.add_obstacle(fixed, 0.03).unwrap();
+        let s = scene.fluid.s.clone();
+        scene.step_simulation(1.0 / 60.0);
+
+        scene.move_obstacle(0, pos + Vec2::new(0.2, 0.05), false);
+        scene.move_obstacle(1, fixed + Vec2::new(0.1, 0.0), false);
+        scene.step_simulation(1.0 / 60.0);
+        // Back over the step after the drag, then over the one before it
+        assert!(scene.step_back() && scene.step_back());
+        assert_eq!(scene.obstacles()[0].pos, pos);
+        assert_eq!(scene.obstacles()[1].pos, fixed);
+        assert_eq!(scene.fluid.s, s, "the solid cells no longer match the restored obstacles");
+    }
+
+    #[test]
+    fn draw_writes_every_pixel_up_to_the_image_edges() {
+        for stride in [None, Some(3)] {
+            let mut scene = FluidScene::new(320.0, 180.0, SceneType::WindTunnel);
+            scene.render_stride = stride;
+            let (width, height) = (scene.width as usize, scene.height as usize);
+            // Drawn pixels are opaque, so a zero alpha marks one that was never written
+            let mut buffer = vec![0; 4 * width * height];
+            scene.draw(&mut buffer);
+
+            let edges = (0..width).flat_map(|x| [(x, 0), (x, height - 1)])
+                .chain((0..height).flat_map(|y| [(0, y), (width - 1, y)]));
+            for (x, y) in edges {
+                assert_eq!(buffer[4 * (y * width + x) + 3], 255, "pixel ({x}, {y}) was not drawn with stride {stride:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn initial_smoke_fills_the_domain_and_streaks_contrast_with_it() {
+        for (fill, streak) in [(1.0, 0.0), (0.0, 1.0)] {
+            let mut scene = FluidScene::with_domain(320.0, 180.0, SceneType::WindTunnel, 60, 29);
+            scene.set_initial_smoke(fill);
+            let n = scene.fluid.num_y;
+            assert!(scene.fluid.m[2 * n..].iter().all(|&m| m == fill), "the domain is not filled with {fill}");
+            assert!(scene.fluid.m[..n].contains(&streak), "the inlet has no streak against a fill of {fill}");
+
+            for _ in 0..20 {
+                scene.step_simulation(1.0 / 60.0);
+            }
+            // The streak is carried into the domain instead of blending into the background
+            let carried = scene.fluid.m[2 * n..].iter().any(|&m| (m - fill).abs() > 0.5);
+            assert!(carried, "no streak entered the domain filled with {fill}");
+        }
+    }
+
+    #[test]
+    fn manual_dt_makes_steps_independent_of_the_frame_time() {
+        let mut steady = centered_tunnel();
+        let mut jittery = centered_tunnel();
+        steady.manual_dt = Some(1.0 / 120.0);
+        jittery.manual_dt = Some(1.0 / 120.0);
+        for frame in 0..10 {
+            let dt = steady.frame_dt(1.0 / 60.0);
+            steady.step_simulation(dt);
+            // Frame times of a stuttering app
+            let dt = jittery.frame_dt(if frame % 2 == 0 { 0.005 } else { 0.04 });
+            jittery.step_simulation(dt);
+        }
+        assert_eq!(steady.fluid.u, jittery.fluid.u);
+        assert_eq!(steady.fluid.v, jittery.fluid.v);
+        assert!((steady.sim_time() - 10.0 / 120.0).abs() < 1e-5);
+
+        steady.manual_dt = None;
+        assert_eq!(steady.frame_dt(0.04), 0.04, "without manual_dt the frame time is used");
+    }
+
+    #[test]
+    fn scene_settings_survive_a_save_and_load() {
+        let mut scene = centered_tunnel();
+        scene.render_stride = Some(2);
+        scene.lic_kernel_length = 32;
+        scene.refinement_factor = 3;
+        scene.refinement_size = 12;
+        scene.set_refinement(true);
+        scene.paint_resistance(Vec2::new(1.0, 0.3), 0.1, 50.0);
+        scene.paint_forced(Vec2::new(1.4, 0.6), 0.1, Some(Vec2::new(0.5, -0.25)));
+
+        let json = scene.settings().to_json().unwrap();
+        let loaded = FluidScene::from_settings(&serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(loaded.render_stride, Some(2));
+        assert_eq!(loaded.lic_kernel_length, 32);
+        assert_eq!((loaded.refinement_factor, loaded.refinement_size), (3, 12));
+        assert!(loaded.refinement.is_some(), "the refinement patch was not rebuilt");
+        assert_eq!(loaded.fluid.resistance, scene.fluid.resistance);
+        assert_eq!(loaded.fluid.forced, scene.fluid.forced);
+        assert_eq!(loaded.settings().to_json().unwrap(), json);
+    }
+
+    #[test]
+    fn advection_stays_in_bounds_and_off_solid_faces() {
+        let mut f = divergent_fluid();
+        // Fast flow everywhere, including the solid faces, so back traces leave the domain
+        for (k, (u, v)) in f.u.iter_mut().zip(f.v.iter_mut()).enumerate() {
+            *u = if k % 2 == 0 { 40.0 } else { -40.0 };
+            *v = if k % 3 == 0 { -40.0 } else { 40.0 };
+        }
+        let (u, v) = (f.u.clone(), f.v.clone());
+        f.advect_vel(1.0 / 60.0);
+
+        let solid = |cell: usize| f.s[cell] == 0.0;
+        for i in 0..N {
+            for j in 0..N {
+                let cell = i * N + j;
+                if i == 0 || solid(cell) || solid((i - 1) * N + j) {
+                    assert_eq!(f.u[cell], u[cell], "u face ({i}, {j}) next to a solid was advected");
+                }
+                if j == 0 || solid(cell) || solid(cell - 1) {
+                    assert_eq!(f.v[cell], v[cell], "v face ({i}, {j}) next to a solid was advected");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wind_tunnel_runs_headless_and_stays_stable() {
+        let mut scene = centered_tunnel();
+        scene.record_residuals = true;
+        for _ in 0..100 {
+            assert!(scene.step_simulation(1.0 / 60.0));
+        }
+        let fluid = &scene.fluid;
+        assert!(fluid.u.iter().chain(&fluid.v).all(|velocity| velocity.is_finite()), "the velocities blew up");
+
+        // The sweeps of the last solve kept reducing the divergence
+        let residuals = &scene.diagnostics.residual_history;
+        let (first, last) = (residuals[0], residuals[residuals.len() - 1]);
+        assert!(last < 0.5 * first, "the solve only took the divergence from {first} to {last}");
+    }
+
+    // The lexicographic Gauss-Seidel sweep the solver used before it was split into colors
+    fn solve_sequential(f: &mut Fluid, iterations: usize, over_relaxation: f32) {
+        let n = f.num_y;
+        for _ in 0..iterations {
+            for i in 1..f.num_x - 1 {
+                for j in 1..n - 1 {
+                    if f.s[i * n + j] == 0.0 {
+                        continue;
+                    }
+                    let (sx0, sx1) = (f.s[(i - 1) * n + j], f.s[(i + 1) * n + j]);
+                    let (sy0, sy1) = (f.s[i * n + j - 1], f.s[i * n + j + 1]);
+                    let s = sx0 + sx1 + sy0 + sy1;
+                    if s == 0.0 {
+                        continue;
+                    }
+                    let div = f.u[(i + 1) * n + j] - f.u[i * n + j] + f.v[i * n + j + 1] - f.v[i * n + j];
+                    let p = (-div / s) * over_relaxation;
+                    f.u[i * n + j] -= sx0 * p;
+                    f.u[(i + 1) * n + j] += sx1 * p;
+                    f.v[i * n + j] -= sy0 * p;
+                    f.v[i * n + j + 1] += sy1 * p;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn red_black_converges_like_the_sequential_sweep() {
+        for iterations in [5, 20, 60] {
+            for over_relaxation in [1.0, OVERRELAXATION] {
+                let mut sequential = divergent_fluid();
+                solve_sequential(&mut sequential, iterations, over_relaxation);
+                let mut red_black = divergent_fluid();
+                red_black.solve_incompressibility(1.0 / 60.0, iterations as i32, over_relaxation, None);
+
+                // Both orderings share the same asymptotic rate, only the constant differs
+                let (expected, actual) = (max_divergence(&sequential), max_divergence(&red_black));
+                assert!(actual <= 2.5 * expected, "{iterations} iterations at {over_relaxation} left {actual}, the sequential sweep {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn loaded_obstacles_are_limited_like_placed_ones() {
+        let scene = centered_tunnel();
+        let mut settings = scene.settings();
+        let h = scene.fluid.h;
+        let oversized = ObstacleConfig { position: [0.2, 0.5], radius: 10.0, velocity: [0.0; 2], rotation_rate: 0.0 };
+        settings.obstacles = vec![oversized, ObstacleConfig { position: [0.2, 0.3], radius: 0.15, ..oversized }];
+
+        let loaded = FluidScene::from_settings(&settings).unwrap();
+        assert!(loaded.obstacles()[0].radius <= MAX_OBSTACLE_RADIUS * loaded.fluid.num_y as f32 * h);
+        for obstacle in loaded.obstacles() {
+            assert!(obstacle.pos.x - obstacle.radius >= 2.0 * h - 1e-6, "{obstacle:?} overlaps the inlet");
+        }
+    }
+
+    #[test]
+    fn obstacle_ids_index_the_obstacles_even_without_the_draggable_one() {
+        let mut scene = centered_tunnel();
+        let fixed = Vec2::new(0.8, 0.1);
+        let id = scene.add_obstacle(fixed, 0.05).unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(scene.obstacle_at(fixed), Some(id));
+        assert_eq!(scene.obstacle_at(scene.obstacles()[0].pos), Some(0));
+
+        // A removed draggable obstacle can't be picked but keeps its place in the layout
+        scene.set_obstacle_radius(0.0);
+        assert_eq!(scene.obstacle_at(scene.obstacles()[0].pos), None);
+        let loaded = FluidScene::from_settings(&scene.settings()).unwrap();
+        assert_eq!(loaded.obstacles().len(), 2);
+        assert_eq!(loaded.obstacles()[0].radius, 0.0);
+        assert_eq!(loaded.obstacles()[id].pos, fixed);
+    }
+
+    // Closed box holding a single vortex, with the velocity set on the faces directly
+    fn seeded_vortex() -> Fluid {
+        let (cells, h) = (40, 0.025);
+        let mut f = Fluid::new(1000.0, cells, cells, h);
+        for i in 1..cells - 1 {
+            for j in 1..cells - 1 {
+                f.s[i * cells + j] = 1.0;
+            }
+        }
+        let center = 0.5 * cells as f32 * h;
+        let swirl = |x: f32, y: f32| {
+            let (dx, dy) = (x - center, y - center);
+            let falloff = (-(dx * dx + dy * dy) / (0.1 * 0.1)).exp();
+            Vec2::new(-dy, dx) * 20.0 * falloff
+        };
+        for i in 2..cells - 1 {
+            for j in 2..cells - 1 {
+                f.u[i * cells + j] = swirl(i as f32 * h, (j as f32 + 0.5) * h).x;
+                f.v[i * cells + j] = swirl((i as f32 + 0.5) * h, j as f32 * h).y;
+            }
+        }
+        f
+    }
+
+    // Largest |curl| at the interior cell corners
+    fn peak_curl(f: &Fluid) -> f32 {
+        let n = f.num_y;
+        let mut peak = 0.0f32;
+        for i in 2..f.num_x - 1 {
+            for j in 2..n - 1 {
+                let curl = (f.v[i * n + j] - f.v[(i - 1) * n + j] - f.u[i * n + j] + f.u[i * n + j - 1]) / f.h;
+                peak = peak.max(curl.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn vorticity_confinement_keeps_a_vortex_spinning() {
+        let dt = 1.0 / 60.0;
+        let mut f = seeded_vortex();
+        let untouched = f.clone();
+        f.apply_vorticity_confinement(dt, 0.0);
+        assert_eq!((&f.u, &f.v), (&untouched.u, &untouched.v), "a strength of 0 changed the flow");
+
+        let spin_down = |strength: f32| {
+            let mut f = seeded_vortex();
+            for _ in 0..30 {
+                f.apply_vorticity_confinement(dt, strength);
+                f.solve_incompressibility(dt, 40, OVERRELAXATION, None);
+                f.advect_vel(dt);
+            }
+            peak_curl(&f)
+        };
+        let (plain, confined) = (spin_down(0.0), spin_down(1.0));
+        assert!(confined > plain, "confinement left a peak curl of {confined}, without it {plain}");
+    }
+
+    // Closed box whose upper half moves right while the lower half is at rest
+    fn shear_step() -> Fluid {
+        let (cells, h) = (20, 0.05);
+        let mut f = Fluid::new(1000.0, cells, cells, h);
+        for i in 1..cells - 1 {
+            for j in 1..cells - 1 {
+                f.s[i * cells + j] = 1.0;
+                if i > 1 && j >= cells / 2 {
+                    f.u[i * cells + j] = 1.0;
+                }
+            }
+        }
+        f
+    }
+
+    #[test]
+    fn zero_viscosity_leaves_the_velocity_untouched() {
+        let mut f = shear_step();
+        let (u, v) = (f.u.clone(), f.v.clone());
+        f.diffuse_velocity(1.0 / 60.0, 0.0);
+        assert!(f.u.iter().zip(&u).all(|(a, b)| a.to_bits() == b.to_bits()), "u changed without viscosity");
+        assert!(f.v.iter().zip(&v).all(|(a, b)| a.to_bits() == b.to_bits()), "v changed without viscosity");
+    }
+
+    #[test]
+    fn high_viscosity_smooths_a_shear_step() {
+        let mut f = shear_step();
+        let n = f.num_y;
+        let column = (f.num_x / 2) * n;
+        let jump = |f: &Fluid| f.u[column + n / 2] - f.u[column + n / 2 - 1];
+
+        let mut previous = jump(&f);
+        for frame in 0..10 {
+            f.diffuse_velocity(TIMESTEP, 1.0);
+            assert!(f.u.iter().chain(&f.v).all(|velocity| velocity.is_finite()), "frame {frame} blew up");
+            let current = jump(&f);
+            assert!(current < previous, "frame {frame} didn't shrink the jump from {previous}, it is {current}");
+            previous = current;
+        }
+        assert!(previous < 0.25, "the jump is still {previous} after 10 frames");
+    }
+
+    #[test]
+    fn snapshot_continues_the_run_exactly() {
+        let mut scene = centered_tunnel();
+        scene.set_refinement(true);
+        scene.add_obstacle(Vec2::new(1.2, 0.3), 0.05).unwrap();
+        for step in 0..20 {
+            // A moving obstacle leaves a velocity behind that the snapshot has to keep
+            let pos = scene.obstacles()[0].pos + Vec2::new(0.0, if step % 2 == 0 { 0.01 } else { -0.01 });
+            scene.set_obstacle(pos, false);
+            scene.step_simulation(1.0 / 60.0);
+        }
+        scene.advance(0.5 / 60.0, None);
+
+        let json = serde_json::to_string(&scene.snapshot()).unwrap();
+        let mut loaded = FluidScene::from_snapshot(&serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(loaded.obstacles(), scene.obstacles());
+        assert_eq!(loaded.time_accumulator, scene.time_accumulator);
+        for restored in [&mut scene, &mut loaded] {
+            restored.step_simulation(1.0 / 60.0);
+        }
+        assert_eq!(loaded.fluid.u, scene.fluid.u);
+        assert_eq!(loaded.fluid.v, scene.fluid.v);
+        let (fine, loaded_fine) = (&scene.refinement.as_ref().unwrap().fluid, &loaded.refinement.as_ref().unwrap().fluid);
+        assert_eq!(loaded_fine.u, fine.u);
+    }
+
+    #[test]
+    fn colormaps_hit_their_end_and_middle_colors() {
+        let expected = [
+            (ColorMap::SciColor, [[0.0, 0.0, 255.0], [0.0, 255.0, 0.0], [255.0, 0.0, 0.0]]),
+            (ColorMap::Grayscale, [[0.0; 3], [127.5; 3], [255.0; 3]]),
+            (ColorMap::Viridis, [[68.0, 1.0, 84.0], [33.0, 145.0, 140.0], [253.0, 231.0, 37.0]]),
+            (ColorMap::BlackBody, [[0.0; 3], [255.0, 120.0, 0.0], [255.0; 3]]),
+        ];
+        assert_eq!(expected.map(|(map, _)| map), ColorMap::ALL);
+
+        for (map, colors) in expected {
+            for (t, color) in [0.0, 0.5, 1.0].into_iter().zip(colors) {
+                let mapped = map.map(t);
+                // The sci-color ramp stops just short of its last color
+                let close = mapped.iter().zip(color).all(|(a, b)| (a - b).abs() < 0.5);
+                assert!(close, "{map:?} maps {t} to {mapped:?} instead of {color:?}");
+            }
+            assert_eq!(map.map(-1.0), map.map(0.0), "{map:?} doesn't clamp below 0");
+            assert_eq!(map.map(2.0), map.map(1.0), "{map:?} doesn't clamp above 1");
+        }
+    }
+
+    #[test]
+    fn colormap_is_a_view_setting() {
+        let overrides = ViewOverrides { colormap: Some(ColorMap::Viridis), ..default() };
+        let config = ViewConfig { scenes: HashMap::from([(SceneType::Tank, overrides)]) };
+        assert_eq!(config.scene_default(SceneType::Tank).colormap, ColorMap::Viridis);
+        assert_eq!(config.scene_default(SceneType::WindTunnel).colormap, ColorMap::default());
+
+        let mut scene = FluidScene::with_domain(160.0, 90.0, SceneType::WindTunnel, 20, 10);
+        scene.apply_view_settings(config.scene_default(SceneType::Tank));
+        assert_eq!(scene.colormap, ColorMap::Viridis);
+        assert_eq!(scene.view_settings().colormap, ColorMap::Viridis);
+    }
+}
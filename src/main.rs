@@ -1,113 +1,691 @@
 
-use bevy::color::palettes::css::{BLACK, WHITE};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use bevy::color::palettes::css::RED;
 use bevy::prelude::*;
 use bevy::window::PrimaryWindow;
-use bevy::{render::{render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension, TextureFormat}}, window::WindowResized};
+use bevy::{render::{render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension}}, window::WindowResized};
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use eulerian_fluid::{FluidScene, SceneType};
+use egui_plot::{Bar, BarChart, Line, Plot, PlotPoints};
+use eulerian_fluid::{CircularDomain, ColorMap, FluidScene, FluidTheme, FluidSample, InletSource, ProbeQuantity, RenderFormat, SceneSettings, SceneSnapshot, SceneType, SmokePattern, SymmetryAxis, TimeIntegration, ViewConfig, ViewSettings, WallSmokeMode};
 use bevy_mod_picking::prelude::*;
 
-const WORLD_SIZE: (f32, f32) = (320.0, 180.0);
+// Default size of the image each scene is drawn into, which the sprite shows 1:1 in world units.
+// Both it and the simulated grid can be changed through SceneConfig
+const DEFAULT_RENDER_SIZE: (f32, f32) = (320.0, 180.0);
+// Fewest interior cells per side accepted for --cells, smaller grids can't fit the scene setups
+const MIN_CELLS: usize = 10;
+// Image size and cells across the height of the coarse preview
+const PREVIEW_SIZE: (f32, f32) = (80.0, 45.0);
+const PREVIEW_RESOLUTION: f32 = 25.0;
 
 mod eulerian_fluid;
 
 fn main() {
+    // `--seed <n>` makes a session's random perturbations reproducible
+    let args: Vec<String> = std::env::args().collect();
+    let seed = args.iter()
+        .position(|arg| arg == "--seed")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(RngResource::DEFAULT_SEED);
+    // `--cells <x>x<y>` simulates on a fixed grid regardless of the image size
+    let sim_domain = args.iter()
+        .position(|arg| arg == "--cells")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.split_once('x'))
+        .and_then(|(x, y)| Some((x.parse::<usize>().ok()?, y.parse::<usize>().ok()?)));
+    if let Some((num_x, num_y)) = sim_domain {
+        if num_x < MIN_CELLS || num_y < MIN_CELLS {
+            eprintln!("--cells {num_x}x{num_y} is too small, each side needs at least {MIN_CELLS} cells");
+            std::process::exit(2);
+        }
+    }
+    // `--render-size <width>x<height>` sets the image the scenes are drawn into, in pixels
+    let render_size = args.iter()
+        .position(|arg| arg == "--render-size")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.split_once('x'))
+        .and_then(|(width, height)| Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)))
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .map_or(DEFAULT_RENDER_SIZE, |(width, height)| (width as f32, height as f32));
+    // `--obstacle-position <x>,<y>` and `--obstacle-radius <r>` set the initial obstacle, the
+    // position in world units from the center of the image
+    let obstacle_position = args.iter()
+        .position(|arg| arg == "--obstacle-position")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.split_once(','))
+        .and_then(|(x, y)| Some(Vec2::new(x.parse().ok()?, y.parse().ok()?)))
+        .unwrap_or(Vec2::ZERO);
+    let obstacle_radius = args.iter()
+        .position(|arg| arg == "--obstacle-radius")
+        .and_then(|index| args.get(index + 1))
+        .and_then(|value| value.parse::<f32>().ok())
+        .filter(|radius| *radius > 0.0);
+    // `--view-config <path>` sets the view each scene type starts with
+    let view_config_file = args.iter()
+        .position(|arg| arg == "--view-config")
+        .and_then(|index| args.get(index + 1))
+        .cloned();
+
     App::new()
         .add_plugins((DefaultPlugins.set(ImagePlugin::default_nearest()), EguiPlugin, DefaultPickingPlugins))
         .add_systems(Startup, setup_scene)
-        .add_systems(FixedUpdate, update_fluid_simulation)
+        .configure_sets(FixedUpdate, FluidSet::Step)
+        .configure_sets(PostUpdate, FluidSet::Draw)
+        .add_systems(FixedUpdate, update_fluid_simulation.in_set(FluidSet::Step))
+        .add_systems(FixedUpdate, update_streakline.after(FluidSet::Step))
+        .add_systems(FixedUpdate, update_preview.after(FluidSet::Step))
+        .add_systems(FixedUpdate, update_lic.after(FluidSet::Step))
+        .add_systems(FixedUpdate, update_iteration_comparison.after(FluidSet::Step))
+        .add_systems(FixedUpdate, record_obstacle_history.after(FluidSet::Step))
+        .add_systems(FixedUpdate, log_probe.after(FluidSet::Step))
+        .add_systems(FixedUpdate, record_force_coefficients.after(FluidSet::Step))
         .add_systems(Update, fit_window)
+        .add_systems(Update, sync_comparison_scene)
         .add_systems(Update, ui_system)
-        .add_systems(PostUpdate, draw_scene_gizmos)
+        .add_systems(Update, copy_to_clipboard)
+        .add_systems(Update, apply_theme)
+        .add_systems(Update, apply_origin_offset)
+        .add_systems(Update, draw_scene_label.after(ui_system))
+        .add_systems(Update, probe_cursor.before(ui_system))
+        .add_event::<CopyImageToClipboard>()
+        .add_systems(PostUpdate, (draw_scene_gizmos, draw_streakline, draw_probe).in_set(FluidSet::Draw))
         .insert_resource(UiState {
             selected_scene: SceneType::WindTunnel,
+            tool: Tool::Obstacle,
+            keep_view_settings: true,
+            view: ViewSettings::scene_default(SceneType::WindTunnel),
+            theme: FluidTheme::default(),
+            obstacle_file: "obstacles.json".to_string(),
+            settings_file: "scene.json".to_string(),
+            state_file: "state.json".to_string(),
+            smoke_pattern: SmokePattern::Checkerboard,
+            dye_color: [0, 0, 0],
+            label: SceneLabel::default(),
         })
         .insert_resource(WindowInformation::default())
         .insert_resource(ObstacleInformation::default())
+        .insert_resource(SceneConfig {
+            obstacle_position,
+            obstacle_radius,
+            sim_domain,
+            render_size,
+            view_config_file,
+            ..default()
+        })
+        .insert_resource(Streakline::default())
+        .insert_resource(PorousBrush::default())
+        .insert_resource(ForcedBrush::default())
+        .insert_resource(ComparisonMode::default())
+        .insert_resource(Preview::default())
+        .insert_resource(FieldViews::default())
+        .insert_resource(ProbeLogger::default())
+        .insert_resource(ForceLog::default())
+        .insert_resource(RngResource::new(seed))
         .run();
 }
 
+// Labels for scheduling other systems around the simulation
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+enum FluidSet {
+    // Advances every scene and renders its field into the scene image (FixedUpdate)
+    Step,
+    // Draws obstacle, velocity and streakline overlays (PostUpdate)
+    Draw,
+}
+
 #[derive(Resource, Default)]
 struct WindowInformation {
     scale: (f32, f32),
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource)]
 struct ObstacleInformation {
     world_position: Vec2,
+    // Id of the obstacle the current drag moves, picked when the drag starts
+    dragged: Option<usize>,
+    // Recent world positions, newest first, drawn as fading ghost outlines
+    history: VecDeque<Vec2>,
+    ghost_count: usize,
+    // Opacity of the newest ghost, older ones fade out linearly
+    ghost_fade: f32,
+}
+
+impl Default for ObstacleInformation {
+    fn default() -> Self {
+        ObstacleInformation {
+            world_position: Vec2::ZERO,
+            dragged: None,
+            history: VecDeque::new(),
+            ghost_count: 0,
+            ghost_fade: 0.5,
+        }
+    }
+}
+
+// Initial obstacle placement used whenever a scene is created
+#[derive(Resource, Default)]
+struct SceneConfig {
+    // World position of the obstacle, (0, 0) is the center of the domain
+    obstacle_position: Vec2,
+    // Overrides the scene's default obstacle radius when set
+    obstacle_radius: Option<f32>,
+    // Radius of a round fluid region centered in the grid, None keeps the scene's rectangle
+    circular_domain_radius: Option<f32>,
+    // Smoke fill of new scenes, None keeps the clear default
+    initial_smoke: Option<f32>,
+    // Shift of the scenes and their overlays in world units, (0, 0) centers the main scene
+    origin_offset: Vec2,
+    // Interior cells of the simulated grid, None uses the scene type's resolution at the image aspect
+    sim_domain: Option<(usize, usize)>,
+    // Size of the image each scene is drawn into, independent of the simulated grid
+    render_size: (f32, f32),
+    // Per scene view defaults, read from view_config_file at startup when given
+    view_config_file: Option<String>,
+    view_config: ViewConfig,
+}
+
+impl SceneConfig {
+    fn new_scene(&self, scene_type: SceneType) -> FluidScene {
+        let mut scene = match self.sim_domain {
+            Some((num_x, num_y)) => FluidScene::with_domain(self.render_size.0, self.render_size.1, scene_type, num_x, num_y),
+            None => FluidScene::new(self.render_size.0, self.render_size.1, scene_type),
+        };
+        scene.apply_view_settings(self.view_config.scene_default(scene_type));
+        if let Some(initial_smoke) = self.initial_smoke {
+            scene.set_initial_smoke(initial_smoke);
+        }
+        scene
+    }
+
+    // Where the comparison scene is placed relative to the main one
+    fn comparison_offset(&self) -> Vec2 {
+        Vec2::new(self.render_size.0, 0.0)
+    }
+
+    fn circular_domain(&self, scene: &FluidScene) -> Option<CircularDomain> {
+        self.circular_domain_radius.map(|radius| CircularDomain {
+            center: scene.domain_center(),
+            radius,
+        })
+    }
+}
+
+// Runs a second scene next to the main one for A/B comparisons
+#[derive(Resource, Default)]
+struct ComparisonMode {
+    enabled: bool,
+}
+
+// Coarse copy of the main scene for quickly previewing the flow regime of its settings
+#[derive(Resource, Default)]
+struct Preview {
+    enabled: bool,
+    scene: Option<FluidScene>,
+}
+
+// Images of the main scene shown in the configuration window, one pixel per interior cell
+#[derive(Resource, Default)]
+struct FieldViews {
+    // Line integral convolution
+    lic: Option<Handle<Image>>,
+    // Divergence left by each solver iteration count, side by side
+    iteration_comparison: Option<Handle<Image>>,
+    // Frame the iteration comparison image was drawn for
+    iteration_comparison_frame: Option<usize>,
+}
+
+// Pixels between the panels of the iteration comparison
+const COMPARISON_GAP: usize = 2;
+
+// Requests copying the rendered field to the clipboard
+#[derive(Event)]
+struct CopyImageToClipboard;
+
+// Marks the comparison scene and its sprite
+#[derive(Component)]
+struct ComparisonScene;
+
+// What clicking and dragging on the simulation does
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Tool {
+    Obstacle,
+    Streakline,
+    PorousBrush,
+    ForcedVelocity,
+    Probe,
+}
+
+// Dye released continuously from a single seed point, newest point first
+#[derive(Resource)]
+struct Streakline {
+    // Seed point in simulation coordinates
+    seed: Option<Vec2>,
+    // Position and seconds since release
+    points: VecDeque<(Vec2, f32)>,
+    // Maximum number of points kept before the oldest are dropped
+    max_length: usize,
+    // Points older than this are dropped, in seconds
+    max_age: f32,
+    // Colors points by age with the scientific colormap instead of fading them out
+    color_by_age: bool,
+}
+
+impl Default for Streakline {
+    fn default() -> Self {
+        Streakline {
+            seed: None,
+            points: VecDeque::new(),
+            max_length: 300,
+            max_age: 10.0,
+            color_by_age: false,
+        }
+    }
+}
+
+#[derive(Resource)]
+struct PorousBrush {
+    // Radius in simulation units
+    radius: f32,
+    // Drag coefficient painted into cells, 0 erases
+    resistance: f32,
+}
+
+impl Default for PorousBrush {
+    fn default() -> Self {
+        PorousBrush {
+            radius: 0.1,
+            resistance: 20.0,
+        }
+    }
+}
+
+// Paints regions pinned to a fixed velocity
+#[derive(Resource)]
+struct ForcedBrush {
+    // Radius in simulation units
+    radius: f32,
+    velocity: Vec2,
+    // Releases painted cells instead of forcing them
+    erase: bool,
+}
+
+impl Default for ForcedBrush {
+    fn default() -> Self {
+        ForcedBrush {
+            radius: 0.05,
+            velocity: Vec2::new(2.0, 0.0),
+            erase: false,
+        }
+    }
+}
+
+impl ForcedBrush {
+    fn paint(&self, scene: &mut FluidScene, pos: Vec2) {
+        let velocity = if self.erase { None } else { Some(self.velocity) };
+        scene.paint_forced(pos, self.radius, velocity);
+    }
+}
+
+// Writes the main scene's fields at a probe point to a CSV file every step while recording
+#[derive(Resource)]
+struct ProbeLogger {
+    // Probe point in simulation coordinates
+    point: Option<Vec2>,
+    path: String,
+    writer: Option<BufWriter<File>>,
+    rows_since_flush: usize,
+    grid: ProbeGrid,
+    // Main scene's fields under the mouse cursor
+    cursor_sample: Option<FluidSample>,
+}
+
+// Lattice of markers over the main scene colored by a quantity, for reading rough values at a glance
+struct ProbeGrid {
+    enabled: bool,
+    // Distance between markers in simulation units
+    spacing: f32,
+    quantity: ProbeQuantity,
+}
+
+impl Default for ProbeLogger {
+    fn default() -> Self {
+        ProbeLogger {
+            point: None,
+            path: "probe.csv".to_string(),
+            writer: None,
+            rows_since_flush: 0,
+            cursor_sample: None,
+            grid: ProbeGrid {
+                enabled: false,
+                spacing: 0.1,
+                quantity: ProbeQuantity::Speed,
+            },
+        }
+    }
+}
+
+impl ProbeLogger {
+    // Rows written between flushes, so little is lost if the app goes down mid run
+    const FLUSH_INTERVAL: usize = 60;
+
+    fn start(&mut self) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writeln!(writer, "sim_time,u,v,p,m")?;
+        self.writer = Some(writer);
+        self.rows_since_flush = 0;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(err) = writer.flush() {
+                warn!("Could not flush the probe log {}: {err}", self.path);
+            }
+        }
+    }
+
+    fn record(&mut self, scene: &FluidScene) -> std::io::Result<()> {
+        let (Some(point), Some(writer)) = (self.point, self.writer.as_mut()) else {
+            return Ok(());
+        };
+        let fluid = &scene.fluid;
+        let u = fluid.sample_field(point.x, point.y, eulerian_fluid::Field::U);
+        let v = fluid.sample_field(point.x, point.y, eulerian_fluid::Field::V);
+        let p = fluid.sample_pressure(point.x, point.y);
+        let m = fluid.sample_field(point.x, point.y, eulerian_fluid::Field::S);
+        writeln!(writer, "{},{},{},{},{}", scene.sim_time(), u, v, p, m)?;
+
+        self.rows_since_flush += 1;
+        if self.rows_since_flush >= Self::FLUSH_INTERVAL {
+            writer.flush()?;
+            self.rows_since_flush = 0;
+        }
+        Ok(())
+    }
+}
+
+// Drag and lift coefficients of the main scene's obstacle over time
+#[derive(Resource)]
+struct ForceLog {
+    recording: bool,
+    // (sim time, drag coefficient, lift coefficient)
+    samples: Vec<(f32, f32, f32)>,
+    path: String,
+}
+
+impl Default for ForceLog {
+    fn default() -> Self {
+        ForceLog {
+            recording: false,
+            samples: Vec::new(),
+            path: "forces.csv".to_string(),
+        }
+    }
+}
+
+impl ForceLog {
+    fn export(&self) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writeln!(writer, "sim_time,drag_coefficient,lift_coefficient")?;
+        for (time, drag, lift) in &self.samples {
+            writeln!(writer, "{},{},{}", time, drag, lift)?;
+        }
+        writer.flush()
+    }
+}
+
+// Single seedable source for every random perturbation, so equal seeds and inputs give equal runs
+#[derive(Resource)]
+struct RngResource {
+    seed: u64,
+    state: u64,
+}
+
+impl RngResource {
+    const DEFAULT_SEED: u64 = 0x5eed;
+
+    fn new(seed: u64) -> Self {
+        RngResource { seed, state: seed }
+    }
+
+    // SplitMix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// Places the configured obstacle into a freshly created scene, returning its world position
+fn place_initial_obstacle(scene: &mut FluidScene, config: &SceneConfig) -> Vec2 {
+    if config.circular_domain_radius.is_some() {
+        scene.set_circular_domain(config.circular_domain(scene));
+    }
+    if let Some(radius) = config.obstacle_radius {
+        // Clamped like the radius slider, the obstacle itself is placed below
+        scene.set_obstacle_radius(radius);
+    }
+
+    let mut world_position = config.obstacle_position;
+    if !scene.obstacle_in_bounds(scene.world_to_pos(world_position)) {
+        warn!("Configured obstacle position {} is outside of the domain, using the center instead", world_position);
+        world_position = Vec2::ZERO;
+    }
+    let pos = scene.world_to_pos(world_position);
+    scene.set_obstacle(pos, true);
+
+    world_position
 }
 
 fn setup_scene(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
+    mut obstacle_info: ResMut<ObstacleInformation>,
+    mut config: ResMut<SceneConfig>,
+    mut ui_state: ResMut<UiState>,
 ) {
     commands.spawn(Camera2dBundle::default());
 
-    // Sets up an image
-    let image = Image::new(
-    Extent3d {
-            width: WORLD_SIZE.0 as u32,
-            height: WORLD_SIZE.1 as u32,
-            ..default()
-        },
-        TextureDimension::D2,
-        vec![0; (WORLD_SIZE.0 * WORLD_SIZE.1 * 4.) as usize],
-        TextureFormat::Rgba8Unorm,
-        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
-    );
-    let image_handle = images.add(image);
+    if let Some(path) = config.view_config_file.clone() {
+        match ViewConfig::load(&path) {
+            Ok(view_config) => config.view_config = view_config,
+            Err(err) => warn!("Could not load the view config from {path}, using the built-in defaults: {err}"),
+        }
+    }
 
-    let mut fluid_scene = FluidScene::new(WORLD_SIZE.0, WORLD_SIZE.1, SceneType::WindTunnel);
+    let mut fluid_scene = config.new_scene(SceneType::WindTunnel);
+    fluid_scene.history_depth = eulerian_fluid::HISTORY_DEPTH;
+    ui_state.view = fluid_scene.view_settings();
+
+    let image_handle = images.add(create_render_image(config.render_size, fluid_scene.render_format));
     fluid_scene.image_handle = image_handle.clone();
-    let pos = Vec2::new(
-        (0. + (fluid_scene.width + 3.) / 2.) / fluid_scene.scale,
-        (0. + (fluid_scene.height - 1.) / 2.) / fluid_scene.scale,
-    );
-    fluid_scene.set_obstacle(pos, true);
+    obstacle_info.world_position = place_initial_obstacle(&mut fluid_scene, &config);
 
     commands.spawn(fluid_scene);
 
-    commands.spawn((
+    commands.spawn(scene_sprite(image_handle, config.origin_offset.extend(1.0)));
+}
+
+fn scene_sprite(image_handle: Handle<Image>, translation: Vec3) -> impl Bundle {
+    (
         SpriteBundle {
-            texture: image_handle.clone(),
+            texture: image_handle,
             transform: Transform {
                 scale: Vec3::new(1.0, 1.0, 1.0),
-                translation: Vec3::new(0.0, 0.0, 1.0),
+                translation,
                 ..Default::default()
             },
             ..Default::default()
         },
-        On::<Pointer<Drag>>::run(|
-            // Listener not actually needed
-            _: Listener<Pointer<Drag>>,
-            mut scene: Query<&mut FluidScene>,
-            q_window: Query<&Window, With<PrimaryWindow>>,
-            q_camera: Query<(&Camera, &GlobalTransform)>,
-            mut obstacle_info: ResMut<ObstacleInformation>,
-            | {
-            let mut scene = scene.single_mut();
-
-            // Getting world position
-            let window = q_window.single();
-            let (camera, camera_transform) = q_camera.single();
-            if let Some(world_position) = window.cursor_position()
-                .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
-                .map(|ray| ray.origin.truncate())
-            {
-                obstacle_info.world_position = world_position;
-
-                let pos = world_to_pos(world_position, &scene);
-
-                scene.set_obstacle(pos, false);
-            }
-        }),
-    ));
+        On::<Pointer<Drag>>::run(drag_scene),
+        On::<Pointer<DragEnd>>::run(end_drag),
+        On::<Pointer<Click>>::run(click_scene),
+    )
+}
+
+// Mouse input on any scene's sprite is applied to every scene so comparisons share the same input
+#[allow(clippy::too_many_arguments)]
+fn drag_scene(
+    event: Listener<Pointer<Drag>>,
+    mut scenes: Query<(&mut FluidScene, Has<ComparisonScene>)>,
+    sprites: Query<&Transform>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    mut obstacle_info: ResMut<ObstacleInformation>,
+    mut streakline: ResMut<Streakline>,
+    ui_state: Res<UiState>,
+    brush: Res<PorousBrush>,
+    forced_brush: Res<ForcedBrush>,
+    mut probe: ResMut<ProbeLogger>,
+) {
+    // Getting world position
+    let window = q_window.single();
+    let (camera, camera_transform) = q_camera.single();
+    let Some(world_position) = cursor_world_position(window, camera, camera_transform) else {
+        return;
+    };
+    // Relative to the center of the dragged sprite
+    let cursor_position = world_position - sprite_offset(&sprites, event.listener());
+    // Drags that don't start on an obstacle move the draggable one
+    let dragged = *obstacle_info.dragged.get_or_insert_with(|| {
+        scenes.iter()
+            .find(|(_, comparison)| !comparison)
+            .and_then(|(scene, _)| scene.obstacle_at(scene.world_to_pos(cursor_position)))
+            .unwrap_or(0)
+    });
+
+    for (mut scene, comparison) in scenes.iter_mut() {
+        let pos = scene.world_to_pos(cursor_position);
+
+        match ui_state.tool {
+            // A frozen scene keeps its flow, only the markers move for framing
+            Tool::Obstacle | Tool::PorousBrush | Tool::ForcedVelocity if scene.frozen => {}
+            Tool::Obstacle => {
+                scene.move_obstacle(dragged, pos, false);
+                // The ghosts trail the draggable obstacle, wherever it ended up
+                if !comparison {
+                    obstacle_info.world_position = pos_to_world_exact(scene.obstacles()[0].pos, &scene);
+                }
+            }
+            // Dragging moves the injector without restarting the line
+            Tool::Streakline => {
+                if !comparison {
+                    streakline.seed = Some(pos);
+                }
+            }
+            Tool::PorousBrush => scene.paint_resistance(pos, brush.radius, brush.resistance),
+            Tool::ForcedVelocity => forced_brush.paint(&mut scene, pos),
+            Tool::Probe => {
+                if !comparison {
+                    probe.point = Some(pos);
+                }
+            }
+        }
+    }
+}
+
+// Obstacles come to rest where they are released
+fn end_drag(
+    _event: Listener<Pointer<DragEnd>>,
+    mut scenes: Query<&mut FluidScene>,
+    mut obstacle_info: ResMut<ObstacleInformation>,
+) {
+    let Some(dragged) = obstacle_info.dragged.take() else {
+        return;
+    };
+    // Frozen scenes ignored the drag, so there is nothing to bring to rest
+    for mut scene in scenes.iter_mut().filter(|scene| !scene.frozen) {
+        if let Some(obstacle) = scene.obstacles().get(dragged).copied() {
+            scene.move_obstacle(dragged, obstacle.pos, true);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn click_scene(
+    event: Listener<Pointer<Click>>,
+    mut scenes: Query<(&mut FluidScene, Has<ComparisonScene>)>,
+    sprites: Query<&Transform>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    mut streakline: ResMut<Streakline>,
+    ui_state: Res<UiState>,
+    brush: Res<PorousBrush>,
+    forced_brush: Res<ForcedBrush>,
+    mut probe: ResMut<ProbeLogger>,
+) {
+    let window = q_window.single();
+    let (camera, camera_transform) = q_camera.single();
+    let Some(world_position) = cursor_world_position(window, camera, camera_transform) else {
+        return;
+    };
+    let world_position = world_position - sprite_offset(&sprites, event.listener());
+
+    for (mut scene, comparison) in scenes.iter_mut() {
+        let pos = scene.world_to_pos(world_position);
+
+        match ui_state.tool {
+            Tool::Obstacle => {}
+            Tool::PorousBrush | Tool::ForcedVelocity if scene.frozen => {}
+            // A click releases a fresh streakline
+            Tool::Streakline => {
+                if !comparison {
+                    streakline.seed = Some(pos);
+                    streakline.points.clear();
+                }
+            }
+            Tool::PorousBrush => scene.paint_resistance(pos, brush.radius, brush.resistance),
+            Tool::ForcedVelocity => forced_brush.paint(&mut scene, pos),
+            Tool::Probe => {
+                if !comparison {
+                    probe.point = Some(pos);
+                }
+            }
+        }
+    }
+}
+
+fn sprite_offset(sprites: &Query<&Transform>, sprite: Entity) -> Vec2 {
+    sprites.get(sprite).map_or(Vec2::ZERO, |transform| transform.translation.truncate())
+}
+
+// Image the scene is drawn into, sized for the render format's channel count
+fn create_render_image(size: (f32, f32), format: RenderFormat) -> Image {
+    Image::new(
+        Extent3d {
+            width: size.0 as u32,
+            height: size.1 as u32,
+            ..default()
+        },
+        TextureDimension::D2,
+        vec![0; (size.0 * size.1) as usize * format.channels()],
+        format.texture_format(),
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    )
 }
 
-fn world_to_pos(world: Vec2, scene: &FluidScene) -> Vec2 {
+fn cursor_world_position(window: &Window, camera: &Camera, camera_transform: &GlobalTransform) -> Option<Vec2> {
+    window.cursor_position()
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+        .map(|ray| ray.origin.truncate())
+}
+
+// Inverse of FluidScene::world_to_pos
+fn pos_to_world_exact(pos: Vec2, scene: &FluidScene) -> Vec2 {
     Vec2::new(
-        (world.x + (scene.width + 3.) / 2.) / scene.scale,
-        (world.y + (scene.height - 1.) / 2.) / scene.scale,
+        pos.x * scene.scale - (scene.width + 3.) / 2.,
+        pos.y * scene.scale - (scene.height - 1.) / 2.,
     )
 }
 
@@ -127,59 +705,303 @@ fn pos_to_world_flip_y(pos: Vec2, scene: &FluidScene) -> Vec2 {
 
 fn update_fluid_simulation(
     mut commands: Commands,
-    mut query: Query<(Entity, &mut FluidScene)>,
+    mut query: Query<(Entity, &mut FluidScene, Has<ComparisonScene>)>,
     mut images: ResMut<Assets<Image>>,
     mut obstacle_info: ResMut<ObstacleInformation>,
     time: Res<Time>,
-    ui_state: Res<UiState>,
+    mut ui_state: ResMut<UiState>,
+    config: Res<SceneConfig>,
 ) {
-    for (entity, mut scene) in query.iter_mut() {
-        let dt = time.delta_seconds();
+    for (entity, mut scene, comparison) in query.iter_mut() {
+        let dt = scene.frame_dt(time.delta_seconds());
 
-        let image_data = images.get_mut(&scene.image_handle).unwrap().data.as_mut_slice();
+        let image = images.get_mut(&scene.image_handle).unwrap();
+        if image.texture_descriptor.format != scene.render_format.texture_format() {
+            *image = create_render_image(config.render_size, scene.render_format);
+        }
+        let image_data = image.data.as_mut_slice();
 
         scene.step(dt, image_data);
 
         if ui_state.selected_scene != scene.scene_type {
             // Create a new scene
             commands.entity(entity).despawn();
-            let mut new_scene = FluidScene::new(WORLD_SIZE.0, WORLD_SIZE.1, ui_state.selected_scene);
+            let mut new_scene = config.new_scene(ui_state.selected_scene);
 
-            let pos = Vec2::new(
-                (0. + (scene.width + 3.) / 2.) / scene.scale,
-                (0. + (scene.height - 1.) / 2.) / scene.scale,
-            );
-            new_scene.set_obstacle(pos, true);
+            obstacle_info.world_position = place_initial_obstacle(&mut new_scene, &config);
             new_scene.image_handle = scene.image_handle.clone();
-            commands.spawn(new_scene);
+            new_scene.render_format = scene.render_format;
+            // Only the main scene records history, the comparison scene keeps its depth of 0
+            new_scene.history_depth = scene.history_depth;
+            if comparison {
+                if ui_state.keep_view_settings {
+                    new_scene.apply_view_settings(scene.view_settings());
+                }
+            } else if ui_state.keep_view_settings {
+                new_scene.apply_view_settings(ui_state.view);
+            } else {
+                ui_state.view = new_scene.view_settings();
+            }
+            let mut new_entity = commands.spawn(new_scene);
+            if comparison {
+                new_entity.insert(ComparisonScene);
+            }
+        }
+    }
+}
+
+// Convolves noise seeded from the session seed along the main scene's flow
+fn update_lic(
+    mut views: ResMut<FieldViews>,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    mut images: ResMut<Assets<Image>>,
+    rng: Res<RngResource>,
+) {
+    let scene = scene.single();
+    if !scene.show_lic {
+        views.lic = None;
+        return;
+    }
 
-            obstacle_info.world_position = Vec2::ZERO;
+    let fluid = &scene.fluid;
+    let size = ((fluid.num_x - 2) as f32, (fluid.num_y - 2) as f32);
+    let Some(image) = sized_image(&mut views.lic, &mut images, size) else {
+        return;
+    };
+
+    // Averaging flattens the noise towards its mean, so stretch the values back to full contrast
+    let lic = fluid.line_integral_convolution(rng.seed, scene.lic_kernel_length);
+    let (min, max) = lic.iter().filter(|value| **value > 0.0).fold((1.0f32, 0.0f32), |(min, max), value| (min.min(*value), max.max(*value)));
+    let range = if max > min { max - min } else { 1.0 };
+    for (pixel, value) in image.data.chunks_exact_mut(4).zip(lic) {
+        let shade = if value > 0.0 { 255.0 * (value - min) / range } else { 0.0 };
+        pixel.copy_from_slice(&[shade as u8, shade as u8, shade as u8, 255]);
+    }
+}
+
+// Draws the divergence fields of the latest iteration comparison next to each other on a shared
+// color scale, so fewer iterations show up as hotter
+fn update_iteration_comparison(
+    mut views: ResMut<FieldViews>,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let scene = scene.single();
+    let Some(comparison) = scene.iteration_comparison().filter(|comparison| !comparison.fields.is_empty()) else {
+        views.iteration_comparison = None;
+        return;
+    };
+    if views.iteration_comparison_frame == Some(comparison.frame_nr) {
+        return;
+    }
+    views.iteration_comparison_frame = Some(comparison.frame_nr);
+
+    let panel_width = scene.fluid.num_x - 2;
+    let panel_height = scene.fluid.num_y - 2;
+    let panels = comparison.fields.len();
+    let width = panels * panel_width + panels.saturating_sub(1) * COMPARISON_GAP;
+    let Some(image) = sized_image(&mut views.iteration_comparison, &mut images, (width as f32, panel_height as f32)) else {
+        return;
+    };
+
+    let max = comparison.fields.iter().flat_map(|(_, field)| field.iter()).fold(0.0f32, |max, value| max.max(*value));
+    image.data.fill(0);
+    for (panel, (_, field)) in comparison.fields.iter().enumerate() {
+        let x0 = panel * (panel_width + COMPARISON_GAP);
+        for (k, value) in field.iter().enumerate() {
+            let (row, column) = (k / panel_width, k % panel_width);
+            let [r, g, b] = eulerian_fluid::get_sci_color(*value, 0.0, max);
+            let index = 4 * (row * width + x0 + column);
+            image.data[index..index + 4].copy_from_slice(&[r as u8, g as u8, b as u8, 255]);
+        }
+    }
+}
+
+// Image behind the handle, recreated when missing or of a different size
+fn sized_image<'a>(handle: &mut Option<Handle<Image>>, images: &'a mut Assets<Image>, size: (f32, f32)) -> Option<&'a mut Image> {
+    let resized = match handle.as_ref().and_then(|handle| images.get(handle)) {
+        Some(image) => image.size().as_vec2() != Vec2::new(size.0, size.1),
+        None => true,
+    };
+    if resized {
+        *handle = Some(images.add(create_render_image(size, RenderFormat::Rgba)));
+    }
+    handle.as_ref().and_then(|handle| images.get_mut(handle))
+}
+
+// Steps the coarse preview with the main scene's settings, rebuilding it when the scene type changes
+fn update_preview(
+    mut preview: ResMut<Preview>,
+    primary: Query<&FluidScene, Without<ComparisonScene>>,
+    mut images: ResMut<Assets<Image>>,
+    time: Res<Time>,
+) {
+    if !preview.enabled {
+        preview.scene = None;
+        return;
+    }
+    let Ok(primary) = primary.get_single() else {
+        return;
+    };
+
+    let rebuild = match &preview.scene {
+        Some(scene) => scene.scene_type != primary.scene_type,
+        None => true,
+    };
+    if rebuild {
+        let mut scene = FluidScene::with_resolution(PREVIEW_SIZE.0, PREVIEW_SIZE.1, primary.scene_type, PREVIEW_RESOLUTION);
+        scene.image_handle = images.add(create_render_image(PREVIEW_SIZE, RenderFormat::Rgba));
+        preview.scene = Some(scene);
+    }
+    let Some(scene) = preview.scene.as_mut() else {
+        return;
+    };
+
+    scene.gravity = primary.gravity;
+    scene.num_iters = primary.num_iters;
+    scene.set_over_relaxation(primary.over_relaxation());
+    scene.time_integration = primary.time_integration;
+    scene.obstacle_rotation_rate = primary.obstacle_rotation_rate;
+    scene.trail_decay = primary.trail_decay;
+    scene.max_smoke = primary.max_smoke;
+    scene.inlet_source = primary.inlet_source;
+    scene.theme = primary.theme;
+    scene.apply_view_settings(primary.view_settings());
+    let (obstacle, target) = (scene.obstacles()[0], primary.obstacles()[0]);
+    if obstacle.radius != target.radius || obstacle.pos != target.pos {
+        scene.set_obstacle_radius(target.radius);
+        scene.set_obstacle(target.pos, true);
+    }
+
+    let Some(image) = images.get_mut(&scene.image_handle) else {
+        return;
+    };
+    scene.advance(time.delta_seconds(), Some(image.data.as_mut_slice()));
+}
+
+// Spawns or removes the comparison scene and its sprite when comparison mode is toggled
+fn sync_comparison_scene(
+    mut commands: Commands,
+    mode: Res<ComparisonMode>,
+    mut images: ResMut<Assets<Image>>,
+    primary: Query<&FluidScene, Without<ComparisonScene>>,
+    existing: Query<Entity, With<ComparisonScene>>,
+    config: Res<SceneConfig>,
+) {
+    if !mode.is_changed() {
+        return;
+    }
+    if !mode.enabled {
+        for entity in existing.iter() {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+    if !existing.is_empty() {
+        return;
+    }
+
+    let primary = primary.single();
+    let mut scene = config.new_scene(primary.scene_type);
+    scene.render_format = primary.render_format;
+    scene.set_circular_domain(primary.circular_domain());
+    let (draggable, fixed) = primary.obstacles().split_first().unwrap();
+    scene.set_obstacle_radius(draggable.radius);
+    scene.set_obstacle(draggable.pos, true);
+    for obstacle in fixed {
+        scene.add_obstacle(obstacle.pos, obstacle.radius);
+    }
+
+    let image_handle = images.add(create_render_image(config.render_size, scene.render_format));
+    scene.image_handle = image_handle.clone();
+
+    commands.spawn((scene, ComparisonScene));
+    commands.spawn((scene_sprite(image_handle, (config.comparison_offset() + config.origin_offset).extend(1.0)), ComparisonScene));
+}
+
+fn record_obstacle_history(mut obstacle_info: ResMut<ObstacleInformation>) {
+    if obstacle_info.ghost_count == 0 {
+        obstacle_info.history.clear();
+        return;
+    }
+    let position = obstacle_info.world_position;
+    obstacle_info.history.push_front(position);
+    let ghost_count = obstacle_info.ghost_count;
+    obstacle_info.history.truncate(ghost_count);
+}
+
+// Keeps the scene sprites at the configured origin. Pointer input is taken relative to the sprite,
+// so dragging follows without further conversion
+fn apply_origin_offset(config: Res<SceneConfig>, mut sprites: Query<(&mut Transform, Has<ComparisonScene>), With<Sprite>>) {
+    for (mut transform, comparison) in sprites.iter_mut() {
+        let base = if comparison { config.comparison_offset() } else { Vec2::ZERO };
+        let translation = (base + config.origin_offset).extend(transform.translation.z);
+        if transform.translation != translation {
+            transform.translation = translation;
         }
     }
 }
 
 fn draw_scene_gizmos(
     mut gizmos: Gizmos,
-    scene: Query<&FluidScene>,
+    scenes: Query<(&FluidScene, Has<ComparisonScene>)>,
     obstacle_info: Res<ObstacleInformation>,
+    config: Res<SceneConfig>,
 ) {
+    for (scene, comparison) in scenes.iter() {
+        let offset = (if comparison { config.comparison_offset() } else { Vec2::ZERO }) + config.origin_offset;
+        draw_gizmos_for_scene(&mut gizmos, scene, &obstacle_info, offset);
+    }
+}
 
-    let scene = scene.single();
-
-    let radius = scene.obstacle_radius + scene.fluid.h;
+fn draw_gizmos_for_scene(gizmos: &mut Gizmos, scene: &FluidScene, obstacle_info: &ObstacleInformation, offset: Vec2) {
+    // Overlays are laid out for the full domain and would not line up with a zoomed render
+    if scene.render_region.is_some() {
+        return;
+    }
 
+    let theme = &scene.theme;
     let color = if scene.show_pressure && scene.show_smoke {
-        WHITE
+        theme.gizmo_contrast
     } else {
-        BLACK
+        theme.gizmo
     };
 
-    gizmos.circle_2d(obstacle_info.world_position, scene.scale * radius, color);
+    for (id, obstacle) in scene.obstacles().iter().enumerate().filter(|(_, obstacle)| obstacle.radius > 0.0) {
+        let radius = scene.scale * (obstacle.radius + scene.fluid.h);
+        let center = pos_to_world_exact(obstacle.pos, scene) + offset;
+        gizmos.circle_2d(center, radius, color);
+        // Ghosts trail the draggable obstacle, and a spoke shows its orientation when it spins
+        if id != 0 {
+            continue;
+        }
+        let ghosts = obstacle_info.history.len() as f32;
+        for (k, ghost) in obstacle_info.history.iter().enumerate() {
+            let alpha = obstacle_info.ghost_fade * (1.0 - k as f32 / ghosts);
+            gizmos.circle_2d(*ghost + offset, radius, Srgba { alpha, ..color });
+        }
+        if scene.obstacle_rotation_rate != 0.0 {
+            let spoke = Vec2::from_angle(scene.obstacle_angle()) * radius;
+            gizmos.line_2d(center, center + spoke, color);
+        }
+    }
+
+    if let Some(patch) = &scene.refinement {
+        let extent = patch.size as f32 * scene.fluid.h;
+        let center = Vec2::new(patch.start.0 as f32, patch.start.1 as f32) * scene.fluid.h + 0.5 * extent;
+        gizmos.rect_2d(
+            pos_to_world_flip_y(center * scene.scale, scene) + offset,
+            0.0,
+            Vec2::splat(extent * scene.scale),
+            color,
+        );
+    }
 
     let fluid = &scene.fluid;
     if scene.show_velocities {
         let n = fluid.num_y;
         let h = fluid.h;
+        let arrow_scale = scene.effective_arrow_scale();
 
         for i in 0..fluid.num_x {
             for j in 0..fluid.num_y {
@@ -189,23 +1011,23 @@ fn draw_scene_gizmos(
                 // X arrow
                 let y = scene.c_y((j as f32 + 0.5) * h, scene.height, scene.scale);
                 let x0 = scene.c_x(i as f32 * h, scene.scale);
-                let x1 = scene.c_x(i as f32 * h + u * 0.01, scene.scale);
+                let x1 = scene.c_x(i as f32 * h + u * arrow_scale, scene.scale);
 
                 gizmos.arrow_2d(
-                    pos_to_world(Vec2::new(x0, y), scene),
-                    pos_to_world(Vec2::new(x1, y), scene),
-                    BLACK
+                    pos_to_world(Vec2::new(x0, y), scene) + offset,
+                    pos_to_world(Vec2::new(x1, y), scene) + offset,
+                    theme.gizmo
                 );
 
                 // Y arrow
                 let x = scene.c_x((i as f32 + 0.5) * h, scene.scale);
                 let y0 = scene.c_y(j as f32 * h, scene.height, scene.scale);
-                let y1 = scene.c_y(j as f32 * h + v * 0.01, scene.height, scene.scale);
+                let y1 = scene.c_y(j as f32 * h + v * arrow_scale, scene.height, scene.scale);
 
                 gizmos.arrow_2d(
-                    pos_to_world(Vec2::new(x, y0), scene),
-                    pos_to_world(Vec2::new(x, y1), scene),
-                    BLACK
+                    pos_to_world(Vec2::new(x, y0), scene) + offset,
+                    pos_to_world(Vec2::new(x, y1), scene) + offset,
+                    theme.gizmo
                 );
             }
         }
@@ -230,9 +1052,9 @@ fn draw_scene_gizmos(
                     if x1 > fluid.num_x as f32 * fluid.h { break; }
 
                     gizmos.arrow_2d(
-                        pos_to_world_flip_y((Vec2::new(x, y)) * scene.scale, scene),
-                        pos_to_world_flip_y((Vec2::new(x1, y1)) * scene.scale, scene),
-                        BLACK
+                        pos_to_world_flip_y((Vec2::new(x, y)) * scene.scale, scene) + offset,
+                        pos_to_world_flip_y((Vec2::new(x1, y1)) * scene.scale, scene) + offset,
+                        theme.gizmo
                     );
                     x = x1;
                     y = y1;
@@ -242,56 +1064,1132 @@ fn draw_scene_gizmos(
     }
 }
 
-// Scale the image to fit the window (integer scaling)
+// Advects every streakline point through the flow and releases a new one at the seed
+fn update_streakline(
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    mut streakline: ResMut<Streakline>,
+    time: Res<Time>,
+) {
+    let Some(seed) = streakline.seed else {
+        return;
+    };
+
+    let fluid = &scene.single().fluid;
+    let dt = time.delta_seconds();
+    let max_x = fluid.num_x as f32 * fluid.h;
+    let max_y = fluid.num_y as f32 * fluid.h;
+
+    for (point, age) in streakline.points.iter_mut() {
+        let u = fluid.sample_field(point.x, point.y, eulerian_fluid::Field::U);
+        let v = fluid.sample_field(point.x, point.y, eulerian_fluid::Field::V);
+        *point += Vec2::new(u, v) * dt;
+        *age += dt;
+    }
+    let max_age = streakline.max_age;
+    streakline.points.retain(|(p, age)| p.x >= 0.0 && p.x <= max_x && p.y >= 0.0 && p.y <= max_y && *age <= max_age);
+
+    streakline.points.push_front((seed, 0.0));
+    let max_length = streakline.max_length;
+    streakline.points.truncate(max_length);
+}
+
+fn draw_streakline(
+    mut gizmos: Gizmos,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    streakline: Res<Streakline>,
+    config: Res<SceneConfig>,
+) {
+    let scene = scene.single();
+    let offset = config.origin_offset;
+
+    if let Some(seed) = streakline.seed {
+        gizmos.circle_2d(pos_to_world_flip_y(seed * scene.scale, scene) + offset, 2.0, RED);
+    }
+
+    // Older parts of the line fade out, or run through the colormap when colored by age
+    let len = streakline.points.len() as f32;
+    gizmos.linestrip_gradient_2d(streakline.points.iter().enumerate().map(|(k, (point, age))| {
+        let color = if streakline.color_by_age {
+            let [r, g, b] = eulerian_fluid::get_sci_color(*age, 0.0, streakline.max_age);
+            Srgba::rgb(r / 255.0, g / 255.0, b / 255.0)
+        } else {
+            Srgba { alpha: 1.0 - k as f32 / len, ..scene.theme.gizmo }
+        };
+        (pos_to_world_flip_y(*point * scene.scale, scene) + offset, color)
+    }));
+}
+
+// Samples the main scene under the cursor for the readout in the ui
+fn probe_cursor(
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    config: Res<SceneConfig>,
+    mut probe: ResMut<ProbeLogger>,
+) {
+    let (Ok(scene), Ok(window), Ok((camera, camera_transform))) = (scene.get_single(), q_window.get_single(), q_camera.get_single()) else {
+        return;
+    };
+    probe.cursor_sample = cursor_world_position(window, camera, camera_transform)
+        .map(|world_position| scene.probe(world_position - config.origin_offset));
+}
+
+fn log_probe(scene: Query<&FluidScene, Without<ComparisonScene>>, mut probe: ResMut<ProbeLogger>) {
+    if probe.writer.is_none() {
+        return;
+    }
+    if let Err(err) = probe.record(scene.single()) {
+        warn!("Stopped logging the probe to {}: {err}", probe.path);
+        probe.writer = None;
+    }
+}
+
+fn record_force_coefficients(scene: Query<&FluidScene, Without<ComparisonScene>>, mut log: ResMut<ForceLog>) {
+    if !log.recording {
+        return;
+    }
+    let scene = scene.single();
+    if let Some(coefficients) = scene.force_coefficients() {
+        log.samples.push((scene.sim_time(), coefficients.x, coefficients.y));
+    }
+}
+
+fn draw_probe(
+    mut gizmos: Gizmos,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    probe: Res<ProbeLogger>,
+    config: Res<SceneConfig>,
+) {
+    let scene = scene.single();
+
+    if probe.grid.enabled {
+        let samples = scene.probe_grid(probe.grid.spacing, probe.grid.quantity);
+        let (min, max) = samples.iter().fold((f32::MAX, f32::MIN), |(min, max), (_, value)| (min.min(*value), max.max(*value)));
+        // Vorticity is colored symmetrically so no rotation stays at the middle of the colormap
+        let (min, max) = match probe.grid.quantity {
+            ProbeQuantity::Vorticity => {
+                let bound = max.abs().max(min.abs());
+                (-bound, bound)
+            }
+            _ => (min, max),
+        };
+        let radius = 0.3 * probe.grid.spacing * scene.scale;
+        for (point, value) in samples {
+            let [r, g, b] = scene.colormap.map_range(value, min, max);
+            let center = pos_to_world_flip_y(point * scene.scale, scene) + config.origin_offset;
+            gizmos.circle_2d(center, radius, Srgba::rgb(r / 255.0, g / 255.0, b / 255.0));
+        }
+    }
+
+    let Some(point) = probe.point else {
+        return;
+    };
+    let color = if probe.writer.is_some() { RED } else { scene.theme.gizmo };
+    gizmos.circle_2d(pos_to_world_flip_y(point * scene.scale, scene) + config.origin_offset, 3.0, color);
+}
+
+// Scale the image to fit the window (integer scaling), making room for the comparison scene
 fn fit_window(
     mut resize_events: EventReader<WindowResized>,
-    mut projections: Query<&mut OrthographicProjection>,
+    comparison: Res<ComparisonMode>,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    mut cameras: Query<(&mut OrthographicProjection, &mut Transform), With<Camera>>,
     mut window_info: ResMut<WindowInformation>,
+    config: Res<SceneConfig>,
+) {
+    if resize_events.read().last().is_none() && !comparison.is_changed() {
+        return;
+    }
+    let Ok(window) = q_window.get_single() else {
+        return;
+    };
+
+    let columns = if comparison.enabled { 2.0 } else { 1.0 };
+    let (width, height) = config.render_size;
+    let h_scale = window.width() / (width * columns);
+    let v_scale = window.height() / height;
+    let (mut projection, mut transform) = cameras.single_mut();
+    let new_scale = 1. / h_scale.min(v_scale).round().max(1.0);
+    projection.scale = new_scale;
+    // Center the camera between the scenes
+    transform.translation.x = (columns - 1.0) * config.comparison_offset().x / 2.0;
+
+    window_info.scale = (window.width() / width, window.height() / height);
+}
+
+// Copies the main scene's image on request or with Ctrl+C, unless egui is taking text input
+fn copy_to_clipboard(
+    mut requests: EventReader<CopyImageToClipboard>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut contexts: EguiContexts,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    images: Res<Assets<Image>>,
 ) {
-    for event in resize_events.read() {
-        let h_scale = event.width / WORLD_SIZE.0 as f32;
-        let v_scale = event.height / WORLD_SIZE.1 as f32;
-        let mut projection = projections.single_mut();
-        let new_scale = 1. / h_scale.min(v_scale).round();
-        projection.scale = new_scale;
+    let requested = requests.read().count() > 0;
+    let ctrl = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let shortcut = ctrl && keys.just_pressed(KeyCode::KeyC) && !contexts.ctx_mut().wants_keyboard_input();
+    if !requested && !shortcut {
+        return;
+    }
+
+    let Some(image) = images.get(&scene.single().image_handle) else {
+        return;
+    };
+    match copy_image_to_clipboard(image) {
+        Ok(()) => info!("Copied the rendered field to the clipboard"),
+        Err(err) => warn!("Could not copy the rendered field to the clipboard: {err}"),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn copy_image_to_clipboard(image: &Image) -> Result<(), String> {
+    let size = image.size();
+    // Single channel formats are expanded to gray
+    let bytes: Vec<u8> = if image.texture_descriptor.format == bevy::render::render_resource::TextureFormat::Rgba8Unorm {
+        image.data.clone()
+    } else {
+        image.data.iter().flat_map(|&value| [value, value, value, 255]).collect()
+    };
+
+    let mut clipboard = arboard::Clipboard::new().map_err(|err| err.to_string())?;
+    clipboard
+        .set_image(arboard::ImageData {
+            width: size.x as usize,
+            height: size.y as usize,
+            bytes: bytes.into(),
+        })
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn copy_image_to_clipboard(_image: &Image) -> Result<(), String> {
+    Err("the clipboard is not available on the web".to_string())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn copy_text_to_clipboard(text: String) -> Result<(), String> {
+    let mut clipboard = arboard::Clipboard::new().map_err(|err| err.to_string())?;
+    clipboard.set_text(text).map_err(|err| err.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn copy_text_to_clipboard(_text: String) -> Result<(), String> {
+    Err("the clipboard is not available on the web".to_string())
+}
 
-        window_info.scale = (event.width / WORLD_SIZE.0 as f32, event.height / WORLD_SIZE.1 as f32);
+// Scene rebuilt from a settings file, or a snapshot file that also restores the flow, taking over
+// the image of the scene it replaces
+fn load_scene(path: &str, snapshot: bool, current: &FluidScene) -> std::io::Result<FluidScene> {
+    let (settings, snapshot) = if snapshot {
+        let snapshot = SceneSnapshot::load(path)?;
+        (snapshot.settings.clone(), Some(snapshot))
+    } else {
+        (SceneSettings::load(path)?, None)
+    };
+    if (settings.width, settings.height) != (current.width, current.height) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("settings are for a {} x {} image, not {} x {}", settings.width, settings.height, current.width, current.height)));
     }
+    let mut scene = match &snapshot {
+        Some(snapshot) => FluidScene::from_snapshot(snapshot)?,
+        None => FluidScene::from_settings(&settings)?,
+    };
+    scene.image_handle = current.image_handle.clone();
+    scene.render_format = current.render_format;
+    scene.theme = current.theme;
+    scene.history_depth = current.history_depth;
+    Ok(scene)
 }
 
 // State for ui
 #[derive(Resource)]
 struct UiState {
     pub selected_scene: SceneType,
+    pub tool: Tool,
+    // Carry the visualization toggles over when the scene type changes
+    pub keep_view_settings: bool,
+    // Visualization toggles of the main scene, written to it every frame so they outlive it
+    pub view: ViewSettings,
+    pub theme: FluidTheme,
+    // Path the obstacle layout is saved to and loaded from
+    pub obstacle_file: String,
+    // Path the full scene settings are exported to and loaded from
+    pub settings_file: String,
+    // Path the settings together with the flow are saved to and restored from
+    pub state_file: String,
+    pub smoke_pattern: SmokePattern,
+    pub dye_color: [u8; 3],
+    pub label: SceneLabel,
+}
+
+// Caption in a corner of the window describing the main scene, so recordings are annotated
+struct SceneLabel {
+    show_label: bool,
+    corner: egui::Align2,
+    // Free text shown above the generated lines
+    caption: String,
+    show_resolution: bool,
+    show_parameters: bool,
+    show_time: bool,
+    // Names the window after the scene type
+    title_from_scene: bool,
+}
+
+impl Default for SceneLabel {
+    fn default() -> Self {
+        Self {
+            show_label: false,
+            corner: egui::Align2::LEFT_BOTTOM,
+            caption: String::new(),
+            show_resolution: true,
+            show_parameters: true,
+            show_time: true,
+            title_from_scene: true,
+        }
+    }
+}
+
+impl SceneLabel {
+    const CORNERS: [(egui::Align2, &'static str); 4] = [
+        (egui::Align2::LEFT_TOP, "Top left"),
+        (egui::Align2::RIGHT_TOP, "Top right"),
+        (egui::Align2::LEFT_BOTTOM, "Bottom left"),
+        (egui::Align2::RIGHT_BOTTOM, "Bottom right"),
+    ];
+
+    fn corner_name(&self) -> &'static str {
+        Self::CORNERS.iter()
+            .find(|(corner, _)| *corner == self.corner)
+            .map_or("", |(_, name)| name)
+    }
+
+    fn compose(&self, scene: &FluidScene) -> String {
+        let mut lines = Vec::new();
+        if !self.caption.is_empty() {
+            lines.push(self.caption.clone());
+        }
+        lines.push(scene.scene_type.to_string());
+        if self.show_resolution {
+            lines.push(format!("{} x {} cells", scene.fluid.num_x - 2, scene.fluid.num_y - 2));
+        }
+        if self.show_parameters {
+            lines.push(format!("dt {:.4} s, {} iterations, over-relaxation {:.2}, gravity {:.2}",
+                scene.dt, scene.num_iters, scene.over_relaxation(), scene.gravity));
+            if scene.has_inflow() {
+                lines.push(format!("Inflow {:.2} m/s", scene.inflow_velocity()));
+            }
+        }
+        if self.show_time {
+            lines.push(format!("t = {:.2} s, frame {}", scene.sim_time(), scene.frame_nr));
+        }
+        lines.join("\n")
+    }
+}
+
+// Draws the scene label and keeps the window title in sync with the scene type
+fn draw_scene_label(
+    mut contexts: EguiContexts,
+    scene: Query<&FluidScene, Without<ComparisonScene>>,
+    ui_state: Res<UiState>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    let Ok(scene) = scene.get_single() else {
+        return;
+    };
+    let label = &ui_state.label;
+
+    if label.title_from_scene {
+        let title = format!("fluid-rs - {}", scene.scene_type);
+        if let Ok(mut window) = windows.get_single_mut() {
+            // Only write on change so the window isn't updated every frame
+            if window.title != title {
+                window.title = title;
+            }
+        }
+    }
+
+    if !label.show_label {
+        return;
+    }
+    let margin = 8.0;
+    let offset = egui::vec2(
+        if label.corner.x() == egui::Align::Max { -margin } else { margin },
+        if label.corner.y() == egui::Align::Max { -margin } else { margin },
+    );
+    let text = label.compose(scene);
+    egui::Area::new(egui::Id::new("scene_label"))
+        .anchor(label.corner, offset)
+        .interactable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.label(text);
+            });
+        });
+}
+
+// Pushes the selected theme to the scenes, the clear color and egui, and the dye color to the scenes
+fn apply_theme(
+    mut contexts: EguiContexts,
+    mut scenes: Query<&mut FluidScene>,
+    mut clear_color: ResMut<ClearColor>,
+    mut applied: Local<Option<FluidTheme>>,
+    ui_state: Res<UiState>,
+) {
+    let theme = ui_state.theme;
+    for mut scene in scenes.iter_mut() {
+        // Only write on change so the scene isn't flagged as changed every frame
+        if scene.theme != theme {
+            scene.theme = theme;
+        }
+        if scene.dye_color != ui_state.dye_color {
+            scene.dye_color = ui_state.dye_color;
+        }
+    }
+
+    // The ui touches UiState every frame, so compare against what was last applied
+    if *applied == Some(theme) {
+        return;
+    }
+    *applied = Some(theme);
+    clear_color.0 = theme.background.into();
+
+    let mut visuals = if theme.dark { egui::Visuals::dark() } else { egui::Visuals::light() };
+    let text = theme.text;
+    visuals.override_text_color = Some(egui::Color32::from_rgb(
+        (255.0 * text.red) as u8,
+        (255.0 * text.green) as u8,
+        (255.0 * text.blue) as u8,
+    ));
+    contexts.ctx_mut().set_visuals(visuals);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ui_system(
     mut contexts: EguiContexts,
-    mut query: Query<&mut FluidScene>,
+    mut query: Query<&mut FluidScene, Without<ComparisonScene>>,
+    mut comparison_query: Query<&mut FluidScene, With<ComparisonScene>>,
     mut ui_state: ResMut<UiState>,
+    mut streakline: ResMut<Streakline>,
+    mut brush: ResMut<PorousBrush>,
+    mut forced_brush: ResMut<ForcedBrush>,
+    mut comparison_mode: ResMut<ComparisonMode>,
+    mut config: ResMut<SceneConfig>,
+    mut obstacle_info: ResMut<ObstacleInformation>,
+    mut preview: ResMut<Preview>,
+    mut probe: ResMut<ProbeLogger>,
+    mut force_log: ResMut<ForceLog>,
+    mut rng: ResMut<RngResource>,
+    field_views: Res<FieldViews>,
+    mut copy_requests: EventWriter<CopyImageToClipboard>,
 ) {
+    let preview_texture = preview.scene.as_ref().map(|scene| contexts.add_image(scene.image_handle.clone_weak()));
+    let lic_texture = field_views.lic.as_ref().map(|handle| contexts.add_image(handle.clone_weak()));
+    let iterations_texture = field_views.iteration_comparison.as_ref().map(|handle| contexts.add_image(handle.clone_weak()));
     let mut scene = query.single_mut();
     egui::Window::new("Configuration").title_bar(false).show(contexts.ctx_mut(), |ui| {
 
         ui.label("Simulation Types");
         let scene_type = &mut ui_state.selected_scene;
         egui::ComboBox::from_id_source("scene_type")
-            .selected_text(format!("{:?}", scene_type))
+            .selected_text(scene_type.to_string())
+            .show_ui(ui, |ui| {
+                for &option in SceneType::all() {
+                    ui.selectable_value(scene_type, option, option.label());
+                }
+            });
+
+        ui.checkbox(&mut ui_state.keep_view_settings, "Keep view settings when switching");
+
+        let theme = &mut ui_state.theme;
+        egui::ComboBox::from_id_source("theme")
+            .selected_text(theme.name)
             .show_ui(ui, |ui| {
-                ui.selectable_value(scene_type, SceneType::WindTunnel, "Wind Tunnel");
-                ui.selectable_value(scene_type, SceneType::HiresTunnel, "Hires Tunnel");
-                ui.selectable_value(scene_type, SceneType::Tank, "Tank");
-                ui.selectable_value(scene_type, SceneType::Paint, "Paint");
+                for option in FluidTheme::ALL {
+                    ui.selectable_value(theme, option, option.name);
+                }
             });
 
+        let mut circular = config.circular_domain_radius.is_some();
+        let mut radius = config.circular_domain_radius.unwrap_or(0.45);
+        let mut domain_changed = ui.checkbox(&mut circular, "Circular domain").changed();
+        if circular {
+            domain_changed |= ui.add(egui::Slider::new(&mut radius, 0.2..=0.5).text("Domain radius")).changed();
+        }
+        if domain_changed {
+            config.circular_domain_radius = circular.then_some(radius);
+            let domain = config.circular_domain(&scene);
+            scene.set_circular_domain(domain);
+            if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                comparison_scene.set_circular_domain(domain);
+            }
+        }
+
+        let mut fill = config.initial_smoke.is_some();
+        let mut initial_smoke = config.initial_smoke.unwrap_or(0.0);
+        let mut fill_changed = ui.checkbox(&mut fill, "Smoke filled domain").changed();
+        if fill {
+            fill_changed |= ui.add(egui::Slider::new(&mut initial_smoke, 0.0..=1.0).text("Initial smoke")).changed();
+        }
+        if fill_changed {
+            config.initial_smoke = fill.then_some(initial_smoke);
+            scene.set_initial_smoke(config.initial_smoke.unwrap_or(1.0));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Domain origin offset");
+            ui.add(egui::DragValue::new(&mut config.origin_offset.x).prefix("x: "));
+            ui.add(egui::DragValue::new(&mut config.origin_offset.y).prefix("y: "));
+        });
+
+        ui.horizontal(|ui| {
+            let smoke_pattern = &mut ui_state.smoke_pattern;
+            egui::ComboBox::from_id_source("smoke_pattern")
+                .selected_text(format!("{:?}", smoke_pattern))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(smoke_pattern, SmokePattern::Checkerboard, "Checkerboard");
+                    ui.selectable_value(smoke_pattern, SmokePattern::Gradient, "Gradient");
+                    ui.selectable_value(smoke_pattern, SmokePattern::Blob, "Blob");
+                    ui.selectable_value(smoke_pattern, SmokePattern::Clear, "Clear");
+                });
+            if ui.button("Stamp smoke").clicked() {
+                scene.fluid.set_smoke_pattern(ui_state.smoke_pattern);
+                if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                    comparison_scene.fluid.set_smoke_pattern(ui_state.smoke_pattern);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Dye color");
+            ui.color_edit_button_srgb(&mut ui_state.dye_color);
+        });
+
         ui.label("Simulation Settings, (Depends on simulation type)");
-        ui.checkbox(&mut scene.show_streamlines, "Show streamlines");
-        ui.checkbox(&mut scene.show_velocities, "Show velocities");
-        ui.checkbox(&mut scene.show_pressure, "Show pressure");
-        ui.checkbox(&mut scene.show_smoke, "Show smoke");
-        ui.checkbox(&mut scene.show_smoke_gradient, "Show smoke gradient");
+        let defaults = config.view_config.scene_default(scene.scene_type);
+        scene_settings_ui(ui, &mut scene, &mut ui_state.view, defaults, "main");
+
+        ui.checkbox(&mut preview.enabled, "Coarse preview");
+        if let Some(texture) = preview_texture {
+            let size = egui::vec2(2.0 * PREVIEW_SIZE.0, 2.0 * PREVIEW_SIZE.1);
+            ui.image(egui::load::SizedTexture::new(texture, size));
+        }
+        if let Some(texture) = lic_texture {
+            let size = egui::vec2(config.render_size.0, config.render_size.1);
+            ui.label("Line integral convolution");
+            ui.image(egui::load::SizedTexture::new(texture, size));
+        }
+
+        // Only write on change so toggling is picked up by change detection
+        let mut compare = comparison_mode.enabled;
+        if ui.checkbox(&mut compare, "Compare side by side").changed() {
+            comparison_mode.enabled = compare;
+        }
+
+        if ui.button("Copy image (Ctrl+C)").clicked() {
+            copy_requests.send(CopyImageToClipboard);
+        }
+
+        ui.collapsing("Scene label", |ui| {
+            let label = &mut ui_state.label;
+            ui.checkbox(&mut label.show_label, "Show label");
+            ui.checkbox(&mut label.title_from_scene, "Window title from scene");
+            egui::ComboBox::from_id_source("label_corner")
+                .selected_text(label.corner_name())
+                .show_ui(ui, |ui| {
+                    for (corner, name) in SceneLabel::CORNERS {
+                        ui.selectable_value(&mut label.corner, corner, name);
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label("Caption");
+                ui.text_edit_singleline(&mut label.caption);
+            });
+            ui.checkbox(&mut label.show_resolution, "Resolution");
+            ui.checkbox(&mut label.show_parameters, "Parameters");
+            ui.checkbox(&mut label.show_time, "Time");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Scene settings");
+            ui.text_edit_singleline(&mut ui_state.settings_file);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Copy config").clicked() {
+                let copied = scene.settings().to_json().map_err(|err| err.to_string()).and_then(copy_text_to_clipboard);
+                match copied {
+                    Ok(()) => info!("Copied the scene settings to the clipboard"),
+                    Err(err) => warn!("Could not copy the scene settings to the clipboard: {err}"),
+                }
+            }
+            if ui.button("Save config").clicked() {
+                match scene.settings().save(&ui_state.settings_file) {
+                    Ok(()) => info!("Saved scene settings to {}", ui_state.settings_file),
+                    Err(err) => warn!("Could not save scene settings to {}: {err}", ui_state.settings_file),
+                }
+            }
+            if ui.button("Load config").clicked() {
+                match load_scene(&ui_state.settings_file, false, &scene) {
+                    Ok(loaded) => {
+                        *scene = loaded;
+                        // Keep the scene switch in update_fluid_simulation from replacing it
+                        ui_state.selected_scene = scene.scene_type;
+                        ui_state.view = scene.view_settings();
+                        obstacle_info.world_position = pos_to_world_flip_y(scene.obstacles()[0].pos * scene.scale, &scene);
+                    }
+                    Err(err) => warn!("Could not load scene settings from {}: {err}", ui_state.settings_file),
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Simulation state");
+            ui.text_edit_singleline(&mut ui_state.state_file);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save state").clicked() {
+                match scene.snapshot().save(&ui_state.state_file) {
+                    Ok(()) => info!("Saved the simulation state to {}", ui_state.state_file),
+                    Err(err) => warn!("Could not save the simulation state to {}: {err}", ui_state.state_file),
+                }
+            }
+            if ui.button("Load state").clicked() {
+                match load_scene(&ui_state.state_file, true, &scene) {
+                    Ok(loaded) => {
+                        *scene = loaded;
+                        ui_state.selected_scene = scene.scene_type;
+                        ui_state.view = scene.view_settings();
+                        obstacle_info.world_position = pos_to_world_flip_y(scene.obstacles()[0].pos * scene.scale, &scene);
+                    }
+                    Err(err) => warn!("Could not load the simulation state from {}: {err}", ui_state.state_file),
+                }
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Obstacle layout");
+            ui.text_edit_singleline(&mut ui_state.obstacle_file);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save obstacles").clicked() {
+                match scene.save_obstacles(&ui_state.obstacle_file) {
+                    Ok(()) => info!("Saved obstacles to {}", ui_state.obstacle_file),
+                    Err(err) => warn!("Could not save obstacles to {}: {err}", ui_state.obstacle_file),
+                }
+            }
+            if ui.button("Load obstacles").clicked() {
+                match scene.load_obstacles(&ui_state.obstacle_file) {
+                    Ok(()) => {
+                        obstacle_info.world_position = pos_to_world_flip_y(scene.obstacles()[0].pos * scene.scale, &scene);
+                    }
+                    Err(err) => warn!("Could not load obstacles from {}: {err}", ui_state.obstacle_file),
+                }
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label(format!("Random seed: {}", rng.seed));
+            if ui.button("Reseed").clicked() {
+                let seed = rng.next_u64();
+                *rng = RngResource::new(seed);
+            }
+        });
+        if ui.button("Add velocity noise").clicked() {
+            scene.add_velocity_noise(0.05, || rng.next_f32());
+        }
+
+        ui.separator();
+        let freeze_label = if scene.frozen { "Unfreeze" } else { "Freeze for capture" };
+        if ui.button(freeze_label).clicked() {
+            let frozen = !scene.frozen;
+            scene.frozen = frozen;
+            if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                comparison_scene.frozen = frozen;
+            }
+            // The obstacle catches up with its marker once the flow runs again
+            if !frozen {
+                let pos = scene.world_to_pos(obstacle_info.world_position);
+                scene.set_obstacle(pos, true);
+                if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                    comparison_scene.set_obstacle(pos, true);
+                }
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut scene.paused, "Pause");
+            if ui.add_enabled(scene.history_len() > 0, egui::Button::new("Step back")).clicked() {
+                scene.paused = true;
+                scene.step_back();
+            }
+            if ui.add_enabled(scene.paused, egui::Button::new("Step forward")).clicked() {
+                scene.request_step_forward();
+            }
+        });
+        ui.horizontal(|ui| {
+            let mut manual = scene.manual_dt.is_some();
+            let mut dt = scene.manual_dt.unwrap_or(scene.fixed_dt);
+            ui.checkbox(&mut manual, "Fixed step");
+            ui.add_enabled(manual, egui::DragValue::new(&mut dt).speed(0.001).clamp_range(0.001..=0.1).suffix(" s"));
+            let manual_dt = manual.then_some(dt);
+            if scene.manual_dt != manual_dt {
+                scene.manual_dt = manual_dt;
+            }
+        });
+        ui.add(egui::Slider::new(&mut scene.history_depth, 0..=600).text("Rewind history (frames)"));
+        ui.label(format!("Frames available to rewind: {}", scene.history_len()));
+        ui.label(format!("Grid: {} x {} cells drawn into {} x {} pixels",
+            scene.fluid.num_x - 2, scene.fluid.num_y - 2, scene.width, scene.height));
+        ui.label(format!("Frame: {}", scene.frame_nr));
+        ui.label(format!("Simulated time: {:.2} s", scene.sim_time()));
+        if scene.is_stable() {
+            ui.label("Stable");
+        } else {
+            ui.colored_label(egui::Color32::RED, "Unstable: the simulation is diverging");
+        }
+        if scene.has_inflow() {
+            ui.label(format!("Pressure drop inlet to outlet: {:.1} Pa", scene.pressure_drop()));
+        }
+        if scene.has_inflow() && scene.obstacles()[0].radius > 0.0 {
+            if ui.button("Trigger vortex shedding").clicked() {
+                scene.trigger_shedding();
+                if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                    comparison_scene.trigger_shedding();
+                }
+            }
+            match (scene.shedding_frequency(), scene.strouhal_number()) {
+                (Some(frequency), Some(strouhal)) => {
+                    ui.label(format!("Shedding frequency: {:.2} Hz (St = {:.3})", frequency, strouhal));
+                }
+                _ => {
+                    ui.label("Shedding frequency: measuring...");
+                }
+            }
+        }
+
+        if scene.force_coefficients().is_some() {
+            egui::CollapsingHeader::new("Drag and lift").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    let label = if force_log.recording { "Stop" } else { "Start" };
+                    if ui.button(label).clicked() {
+                        force_log.recording = !force_log.recording;
+                    }
+                    if ui.button("Clear").clicked() {
+                        force_log.samples.clear();
+                    }
+                    if ui.button("Export CSV").clicked() {
+                        match force_log.export() {
+                            Ok(()) => info!("Saved drag and lift to {}", force_log.path),
+                            Err(err) => warn!("Could not save drag and lift to {}: {err}", force_log.path),
+                        }
+                    }
+                });
+                ui.text_edit_singleline(&mut force_log.path);
+
+                let samples = &force_log.samples;
+                if !samples.is_empty() {
+                    let count = samples.len() as f32;
+                    let mean_drag = samples.iter().map(|&(_, drag, _)| drag).sum::<f32>() / count;
+                    let (min_lift, max_lift) = samples.iter()
+                        .fold((f32::MAX, f32::MIN), |(min, max), &(_, _, lift)| (min.min(lift), max.max(lift)));
+                    ui.label(format!("Mean drag coefficient: {:.3}", mean_drag));
+                    ui.label(format!("Lift coefficient amplitude: {:.3}", 0.5 * (max_lift - min_lift)));
+                }
+
+                let drag: PlotPoints = samples.iter().map(|&(time, drag, _)| [time as f64, drag as f64]).collect();
+                let lift: PlotPoints = samples.iter().map(|&(time, _, lift)| [time as f64, lift as f64]).collect();
+                Plot::new("force_coefficients")
+                    .height(120.0)
+                    .x_axis_label("Simulated time (s)")
+                    .allow_drag(false)
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .show(ui, |plot_ui| {
+                        plot_ui.line(Line::new(drag).name("Drag"));
+                        plot_ui.line(Line::new(lift).name("Lift"));
+                    });
+            });
+        }
+
+        egui::CollapsingHeader::new("Diagnostics").show(ui, |ui| {
+            ui.checkbox(&mut scene.collect_diagnostics, "Collect after each pressure solve");
+            ui.checkbox(&mut scene.clear_trapped_pockets, "Stop flow in trapped fluid pockets");
+            ui.add(egui::Slider::new(&mut scene.histogram_bins, 5..=50).text("Histogram bins"));
+
+            let diagnostics = &scene.diagnostics;
+            ui.label(format!("Max divergence: {:.2e}", diagnostics.max_divergence));
+            ui.label(format!("Fluid regions: {}", diagnostics.fluid_regions));
+
+            let bin_width = diagnostics.max_divergence as f64 / diagnostics.divergence_histogram.len().max(1) as f64;
+            let bars = diagnostics.divergence_histogram.iter().enumerate()
+                .map(|(k, &count)| Bar::new((k as f64 + 0.5) * bin_width, count as f64).width(bin_width))
+                .collect();
+            Plot::new("divergence_histogram")
+                .height(120.0)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars).name("Cells by |divergence|")));
+
+            ui.checkbox(&mut scene.record_residuals, "Record residual after each sweep");
+            if scene.record_residuals {
+                // Log scale makes the linear convergence rate a straight line
+                let points: PlotPoints = scene.diagnostics.residual_history.iter().enumerate()
+                    .map(|(k, &residual)| [(k + 1) as f64, (residual.max(f32::MIN_POSITIVE) as f64).log10()])
+                    .collect();
+                Plot::new("residual_history")
+                    .height(120.0)
+                    .x_axis_label("Iteration")
+                    .y_axis_label("log10 max divergence")
+                    .allow_drag(false)
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .show(ui, |plot_ui| plot_ui.line(Line::new(points)));
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Iteration counts");
+                for iterations in scene.comparison_iterations.iter_mut() {
+                    ui.add(egui::DragValue::new(iterations).clamp_range(1..=1000));
+                }
+            });
+            if ui.button("Compare iteration counts on the next frame").clicked() {
+                scene.request_iteration_comparison();
+            }
+            if let (Some(texture), Some(comparison)) = (iterations_texture, scene.iteration_comparison()) {
+                let counts: Vec<String> = comparison.fields.iter().map(|(iterations, _)| iterations.to_string()).collect();
+                ui.label(format!("|divergence| of frame {} after {} iterations", comparison.frame_nr, counts.join(" / ")));
+                let panels = comparison.fields.len() as f32;
+                let width = panels * (scene.fluid.num_x - 2) as f32 + (panels - 1.0).max(0.0) * COMPARISON_GAP as f32;
+                let size = egui::vec2(width, (scene.fluid.num_y - 2) as f32);
+                ui.image(egui::load::SizedTexture::new(texture, size));
+            }
+
+            ui.checkbox(&mut scene.profile, "Profile step stages");
+            if scene.profile {
+                let stages = scene.timings.stages();
+                let total: f64 = stages.iter().map(|(_, duration)| duration.as_secs_f64() * 1000.0).sum();
+                ui.label(format!("Step: {:.2} ms", total));
+                for (name, duration) in stages {
+                    ui.label(format!("{}: {:.3} ms", name, duration.as_secs_f64() * 1000.0));
+                }
+                let bars = stages.iter().enumerate()
+                    .map(|(k, (name, duration))| Bar::new(k as f64, duration.as_secs_f64() * 1000.0).name(*name))
+                    .collect();
+                Plot::new("step_timings")
+                    .height(120.0)
+                    .x_axis_label("ms")
+                    .allow_drag(false)
+                    .allow_zoom(false)
+                    .allow_scroll(false)
+                    .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars).horizontal().name("Stage time")));
+            }
+        });
+
+        ui.separator();
+        ui.label("Mouse tool");
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut ui_state.tool, Tool::Obstacle, "Obstacle");
+            ui.radio_value(&mut ui_state.tool, Tool::Streakline, "Streakline");
+            ui.radio_value(&mut ui_state.tool, Tool::PorousBrush, "Porous brush");
+            ui.radio_value(&mut ui_state.tool, Tool::ForcedVelocity, "Forced velocity");
+            ui.radio_value(&mut ui_state.tool, Tool::Probe, "Probe");
+        });
+        if ui_state.tool == Tool::Obstacle {
+            // Every obstacle after the draggable one is fixed
+            let fixed = scene.obstacles().len() - 1;
+            ui.horizontal(|ui| {
+                if ui.button("Add fixed obstacle").clicked() {
+                    let (pos, radius) = (scene.domain_center(), scene.obstacles()[0].radius.max(eulerian_fluid::OBSTACLE_RADIUS));
+                    scene.add_obstacle(pos, radius);
+                    if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                        comparison_scene.add_obstacle(pos, radius);
+                    }
+                }
+                if ui.add_enabled(fixed > 0, egui::Button::new("Remove fixed obstacles")).clicked() {
+                    scene.clear_fixed_obstacles();
+                    if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+                        comparison_scene.clear_fixed_obstacles();
+                    }
+                }
+            });
+            ui.label(format!("Fixed obstacles: {fixed}, drag one to move it"));
+        }
+        if ui_state.tool == Tool::Streakline {
+            ui.add(egui::Slider::new(&mut streakline.max_length, 10..=1000).text("Streakline length"));
+            ui.add(egui::Slider::new(&mut streakline.max_age, 0.5..=60.0).text("Maximum age (s)"));
+            ui.checkbox(&mut streakline.color_by_age, "Color by age (young blue, old red)");
+            if let Some(seed) = streakline.seed {
+                ui.label(format!("Seed point: ({:.2}, {:.2})", seed.x, seed.y));
+            }
+            if ui.button("Clear streakline").clicked() {
+                streakline.seed = None;
+                streakline.points.clear();
+            }
+        }
+        if ui_state.tool == Tool::PorousBrush {
+            ui.add(egui::Slider::new(&mut brush.radius, 0.02..=0.3).text("Brush radius"));
+            ui.add(egui::Slider::new(&mut brush.resistance, 0.0..=200.0).text("Resistance"));
+            if ui.button("Clear porous regions").clicked() {
+                scene.fluid.resistance.fill(0.0);
+            }
+        }
+        if ui_state.tool == Tool::ForcedVelocity {
+            ui.add(egui::Slider::new(&mut forced_brush.radius, 0.02..=0.3).text("Brush radius"));
+            ui.add(egui::Slider::new(&mut forced_brush.velocity.x, -5.0..=5.0).text("Velocity x"));
+            ui.add(egui::Slider::new(&mut forced_brush.velocity.y, -5.0..=5.0).text("Velocity y"));
+            ui.checkbox(&mut forced_brush.erase, "Erase");
+            if ui.button("Clear forced regions").clicked() {
+                scene.fluid.forced.fill(None);
+            }
+        }
+        if ui_state.tool == Tool::Probe {
+            match probe.point {
+                Some(point) => ui.label(format!("Probe point: ({:.2}, {:.2})", point.x, point.y)),
+                None => ui.label("Click to place the probe"),
+            };
+            match probe.cursor_sample {
+                Some(sample) if sample.solid => ui.label("Under cursor: solid"),
+                Some(sample) => ui.label(format!("Under cursor: velocity ({:.2}, {:.2}) m/s, pressure {:.1} Pa, smoke {:.2}",
+                    sample.velocity.x, sample.velocity.y, sample.pressure, sample.smoke)),
+                None => ui.label("Under cursor: -"),
+            };
+            ui.horizontal(|ui| {
+                ui.label("Log file");
+                ui.add_enabled(probe.writer.is_none(), egui::TextEdit::singleline(&mut probe.path));
+            });
+            if probe.writer.is_none() {
+                if ui.add_enabled(probe.point.is_some(), egui::Button::new("Start logging")).clicked() {
+                    if let Err(err) = probe.start() {
+                        warn!("Could not start logging the probe to {}: {err}", probe.path);
+                    }
+                }
+            } else if ui.button("Stop logging").clicked() {
+                probe.stop();
+            }
+        }
+        let grid = &mut probe.grid;
+        ui.checkbox(&mut grid.enabled, "Probe grid");
+        if grid.enabled {
+            ui.add(egui::Slider::new(&mut grid.spacing, 0.03..=0.5).text("Probe grid spacing"));
+            egui::ComboBox::from_id_source("probe_quantity")
+                .selected_text(format!("{:?}", grid.quantity))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut grid.quantity, ProbeQuantity::Speed, "Speed");
+                    ui.selectable_value(&mut grid.quantity, ProbeQuantity::Pressure, "Pressure");
+                    ui.selectable_value(&mut grid.quantity, ProbeQuantity::Vorticity, "Vorticity");
+                });
+        }
 
         ui.separator();
+        ui.add(egui::Slider::new(&mut obstacle_info.ghost_count, 0..=120).text("Obstacle ghosts (frames)"));
+        ui.add(egui::Slider::new(&mut obstacle_info.ghost_fade, 0.0..=1.0).text("Ghost opacity"));
         ui.label("Click and drag to move the obstacle");
     });
+    scene.apply_view_settings(ui_state.view);
+
+    if let Ok(mut comparison_scene) = comparison_query.get_single_mut() {
+        egui::Window::new("Comparison (right)").show(contexts.ctx_mut(), |ui| {
+            let defaults = config.view_config.scene_default(comparison_scene.scene_type);
+            let mut view = comparison_scene.view_settings();
+            scene_settings_ui(ui, &mut comparison_scene, &mut view, defaults, "comparison");
+            comparison_scene.apply_view_settings(view);
+        });
+    }
+}
+
+// Per scene settings, shared by the main and comparison windows
+fn scene_settings_ui(ui: &mut egui::Ui, scene: &mut FluidScene, view: &mut ViewSettings, view_defaults: ViewSettings, id: &str) {
+    ui.checkbox(&mut view.show_streamlines, "Show streamlines");
+    ui.checkbox(&mut view.show_velocities, "Show velocities");
+    if view.show_velocities {
+        ui.checkbox(&mut scene.auto_arrow_scale, "Auto scale arrows");
+        ui.add_enabled(
+            !scene.auto_arrow_scale,
+            egui::Slider::new(&mut scene.arrow_scale, 0.001..=0.1).logarithmic(true).text("Arrow scale"),
+        );
+    }
+    ui.checkbox(&mut view.show_pressure, "Show pressure");
+    ui.checkbox(&mut view.show_smoke, "Show smoke");
+    ui.checkbox(&mut view.show_smoke_gradient, "Show smoke gradient");
+    egui::ComboBox::new(format!("colormap_{id}"), "Color map")
+        .selected_text(format!("{:?}", view.colormap))
+        .show_ui(ui, |ui| {
+            for option in ColorMap::ALL {
+                ui.selectable_value(&mut view.colormap, option, format!("{:?}", option));
+            }
+        });
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut view.show_mask, "Show solid mask");
+        if ui.button("Export mask").clicked() {
+            let path = format!("mask_{id}.pgm");
+            match std::fs::write(&path, scene.mask_pgm()) {
+                Ok(()) => info!("Saved the solid mask to {path}"),
+                Err(err) => warn!("Could not save the solid mask to {path}: {err}"),
+            }
+        }
+    });
+    ui.checkbox(&mut scene.show_stream_function, "Show stream function contours");
+    ui.checkbox(&mut scene.show_shear, "Show shear (strain rate)");
+    ui.checkbox(&mut scene.show_lic, "Show line integral convolution");
+    if scene.show_lic {
+        ui.add(egui::Slider::new(&mut scene.lic_kernel_length, 1..=100).text("LIC kernel length"));
+    }
+    ui.checkbox(&mut view.show_courant, "Highlight Courant number > 1");
+    if view.show_courant {
+        ui.label(format!("Max Courant number: {:.2}", scene.fluid.max_courant(scene.dt)));
+    }
+    if ui.button("Reset view to scene defaults").clicked() {
+        *view = view_defaults;
+    }
+    ui.checkbox(&mut scene.smooth_render, "Smooth rendering");
+    let mut auto_stride = scene.render_stride.is_none();
+    let mut stride = scene.effective_render_stride();
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut auto_stride, "Auto render stride");
+        ui.add_enabled(!auto_stride, egui::Slider::new(&mut stride, 1..=8).text("Render stride"));
+    });
+    scene.render_stride = if auto_stride { None } else { Some(stride) };
+
+    // Digital zoom on the field around the obstacle
+    let domain = Vec2::new(scene.width, scene.height) / scene.scale;
+    let mut zoomed = scene.render_region.is_some();
+    let mut zoom = scene.render_region.map_or(3.0, |region| domain.x / region.width());
+    ui.checkbox(&mut zoomed, "Zoom on obstacle");
+    if zoomed {
+        ui.add(egui::Slider::new(&mut zoom, 1.5..=8.0).text("Zoom"));
+        let size = domain / zoom;
+        let center = scene.obstacles()[0].pos.clamp(0.5 * size, domain - 0.5 * size);
+        scene.render_region = Some(Rect::from_center_size(center, size));
+    } else {
+        scene.render_region = None;
+    }
+    ui.checkbox(&mut scene.ghost_boundaries, "Ghost cell obstacle boundaries");
+    ui.add(egui::Slider::new(&mut scene.clamp_border, 0..=10).text("Wall clamp border (cells)"));
+
+    ui.add(egui::Slider::new(&mut scene.num_iters, 1..=200).text("Solver iterations"));
+    let mut over_relaxation = scene.over_relaxation();
+    if ui.add(egui::Slider::new(&mut over_relaxation, 1.0..=eulerian_fluid::MAX_OVERRELAXATION).text("Over-relaxation")).changed() {
+        scene.set_over_relaxation(over_relaxation);
+    }
+    if scene.over_relaxation() > 1.95 {
+        ui.colored_label(egui::Color32::YELLOW, "Over-relaxation close to 2 converges slowly or diverges");
+    }
+    if scene.diagnostics.residual_growing() {
+        ui.colored_label(egui::Color32::RED, "The pressure solve is diverging, lower the over-relaxation");
+    }
+    let mut local_relaxation = scene.local_relaxation();
+    if ui.checkbox(&mut local_relaxation, "Lower relaxation near solids").changed() {
+        scene.set_local_relaxation(local_relaxation);
+    }
+
+    let time_integration = &mut scene.time_integration;
+    egui::ComboBox::new(format!("time_integration_{id}"), "Force integration")
+        .selected_text(format!("{:?}", time_integration))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(time_integration, TimeIntegration::Euler, "Euler");
+            ui.selectable_value(time_integration, TimeIntegration::Verlet, "Verlet");
+        });
+
+    // Example force field, a swirl around the middle of the domain
+    let mut swirl = scene.has_force_field();
+    if ui.checkbox(&mut swirl, "Swirl force field").changed() {
+        let center = scene.domain_center();
+        scene.set_force_field(swirl.then(|| -> eulerian_fluid::ForceField {
+            Box::new(move |_, _, x, y| 5.0 * Vec2::new(center.y - y, x - center.x))
+        }));
+    }
+
+    let enforce_symmetry = &mut scene.enforce_symmetry;
+    egui::ComboBox::new(format!("enforce_symmetry_{id}"), "Enforce symmetry")
+        .selected_text(match enforce_symmetry {
+            Some(axis) => format!("{:?}", axis),
+            None => "Off".to_string(),
+        })
+        .show_ui(ui, |ui| {
+            ui.selectable_value(enforce_symmetry, None, "Off");
+            ui.selectable_value(enforce_symmetry, Some(SymmetryAxis::Horizontal), "Horizontal centerline");
+            ui.selectable_value(enforce_symmetry, Some(SymmetryAxis::Vertical), "Vertical centerline");
+        });
+
+    let mut obstacle_radius = scene.obstacles()[0].radius;
+    if ui.add(egui::Slider::new(&mut obstacle_radius, 0.0..=0.4).text("Obstacle radius")).changed() {
+        scene.set_obstacle_radius(obstacle_radius);
+    }
+    ui.add(egui::Slider::new(&mut scene.obstacle_smoothing, 0.0..=0.95).text("Obstacle velocity smoothing"));
+    ui.add(egui::Slider::new(&mut scene.max_obstacle_speed, 1.0..=50.0).text("Max obstacle speed (m/s)"));
+    if scene.obstacle_too_fast() {
+        ui.colored_label(egui::Color32::YELLOW, "Obstacle is moving too fast, its speed is clamped");
+    }
+    let wall_smoke_mode = &mut scene.wall_smoke_mode;
+    egui::ComboBox::new(format!("wall_smoke_mode_{id}"), "Smoke at walls")
+        .selected_text(format!("{:?}", wall_smoke_mode))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(wall_smoke_mode, WallSmokeMode::Absorb, "Absorb");
+            ui.selectable_value(wall_smoke_mode, WallSmokeMode::Reflect, "Reflect");
+        });
+    ui.add(egui::Slider::new(&mut scene.max_smoke, 0.5..=4.0).text("Max smoke"));
+    ui.add(egui::Slider::new(&mut scene.trail_decay, 0.0..=5.0).text("Smoke trail decay (1/s)"));
+    ui.add(egui::Slider::new(&mut scene.fluid.damping_x, 0.0..=10.0).text("Horizontal damping (1/s)"));
+    ui.add(egui::Slider::new(&mut scene.fluid.damping_y, 0.0..=10.0).text("Vertical damping (1/s)"));
+    ui.add(egui::Slider::new(&mut scene.vorticity_strength, 0.0..=20.0).text("Vorticity confinement"));
+    ui.add(egui::Slider::new(&mut scene.viscosity, 0.0..=0.05).logarithmic(true).text("Viscosity (m^2/s)"));
+    ui.add(egui::Slider::new(&mut scene.obstacle_feather, 0.0..=2.0).text("Obstacle feather (cells)"));
+    if ui.add(egui::Slider::new(&mut scene.obstacle_subsamples, 1..=8).text("Obstacle subsamples per cell side")).changed() {
+        let pos = scene.obstacles()[0].pos;
+        scene.set_obstacle(pos, true);
+    }
+    ui.add(egui::Slider::new(&mut scene.obstacle_rotation_rate, -20.0..=20.0).text("Obstacle spin (rad/s)"));
+
+    let mut refine = scene.refinement.is_some();
+    ui.checkbox(&mut refine, "Refine around obstacle");
+    let factor_changed = ui.add(egui::Slider::new(&mut scene.refinement_factor, 2..=4).text("Refinement factor")).changed();
+    let size_changed = ui.add(egui::Slider::new(&mut scene.refinement_size, 10..=60).text("Refinement patch size")).changed();
+    if refine != scene.refinement.is_some() || (refine && (factor_changed || size_changed)) {
+        scene.set_refinement(refine);
+    }
+
+    let render_format = &mut scene.render_format;
+    egui::ComboBox::new(format!("render_format_{id}"), "Render format")
+        .selected_text(format!("{:?}", render_format))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(render_format, RenderFormat::Rgba, "RGBA");
+            ui.selectable_value(render_format, RenderFormat::Pressure, "Pressure (R8)");
+            ui.selectable_value(render_format, RenderFormat::Smoke, "Smoke (R8)");
+        });
+
+    if scene.scene_type == SceneType::Tank {
+        ui.add(egui::Slider::new(&mut scene.tank_source_rate, 0.0..=5.0).text("Plume source rate (1/s)"));
+        ui.add(egui::Slider::new(&mut scene.tank_drain_speed, 0.0..=1.0).text("Bottom drain speed (m/s)"));
+    }
+
+    if scene.has_inflow() {
+        ui.add(egui::Slider::new(&mut scene.inflow_ramp_seconds, 0.0..=5.0).text("Inflow ramp-up (s)"));
+        ui.horizontal(|ui| {
+            // Fully developed channel flow, 1.5 times the mean speed on the center line
+            if ui.button("Parabolic inlet").clicked() {
+                let num_y = scene.fluid.num_y;
+                let profile: Vec<f32> = (0..num_y)
+                    .map(|j| {
+                        let y = (j as f32 - 0.5) / (num_y - 2) as f32;
+                        6.0 * eulerian_fluid::VELOCITY_IN * (y * (1.0 - y)).max(0.0)
+                    })
+                    .collect();
+                scene.set_inflow_profile(&profile);
+            }
+            if ui.add_enabled(scene.has_inflow_profile(), egui::Button::new("Uniform inlet")).clicked() {
+                scene.clear_inflow_profile();
+            }
+        });
+
+        let inlet_source = &mut scene.inlet_source;
+        egui::ComboBox::new(format!("inlet_source_{id}"), "Inlet smoke")
+            .selected_text(format!("{:?}", inlet_source))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(inlet_source, InletSource::Band, "Band");
+                ui.selectable_value(inlet_source, InletSource::Point, "Point");
+                ui.selectable_value(inlet_source, InletSource::Line, "Line");
+                ui.selectable_value(inlet_source, InletSource::Ring, "Ring");
+            });
+        ui.checkbox(&mut scene.continuous_inlet_smoke, "Release inlet smoke every step");
+        if scene.inlet_source == InletSource::Band {
+            ui.add(egui::Slider::new(&mut scene.inlet_band_fraction, 0.01..=0.5).text("Smoke band height"));
+        }
+    }
 }
\ No newline at end of file